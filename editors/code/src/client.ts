@@ -7,9 +7,10 @@ import {
 	ServerOptions
   } from 'vscode-languageclient/node';
 import * as vscode from 'vscode';
+import { ROOT_SECTION } from './config';
 
 
-export function createClient(serverPath: string, extraEnv: Record<string, any>): LanguageClient {
+export function createClient(serverPath: string, extraEnv: Record<string, any>, initializationOptions: Record<string, any>): LanguageClient {
 	const newEnv = Object.assign({}, process.env);
     Object.assign(newEnv, extraEnv);
 	const run: Executable = {
@@ -27,6 +28,10 @@ export function createClient(serverPath: string, extraEnv: Record<string, any>):
 		documentSelector: [{ scheme: 'file', language: 'rust' }],
 		traceOutputChannel,
 		diagnosticCollectionName: "rust-deadlock-detector",
+		initializationOptions,
+		synchronize: {
+			configurationSection: ROOT_SECTION
+		},
 		errorHandler: {
 			error: (err) => {
 				console.error("lsp client", err);
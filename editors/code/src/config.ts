@@ -1,8 +1,10 @@
 
 import * as vscode from 'vscode';
 
+export const ROOT_SECTION = "rust-deadlock-detector";
+
 export class Config {
-    readonly rootSection = "rust-deadlock-detector";
+    readonly rootSection = ROOT_SECTION;
     constructor(ctx: vscode.ExtensionContext) {
        
     }
@@ -21,6 +23,13 @@ export class Config {
     get luckbud() {
         return this.get<null | string>("luckbud");
     }
+
+    /**
+     * The whole section, sent to the server as initialization options.
+     */
+    get serverSettings(): Record<string, any> {
+        return JSON.parse(JSON.stringify(this.cfg));
+    }
     
     private get cfg(): vscode.WorkspaceConfiguration {
         return vscode.workspace.getConfiguration(this.rootSection);
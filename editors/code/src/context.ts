@@ -35,7 +35,7 @@ export class Context {
             "LD_LIBRARY_PATH": config.dyldLibPath,
             "RUST_LOG":"lsp_server=debug",
             __DL_RUSTC:config.luckbud
-        });
+        }, config.serverSettings);
         const ctx = new Context(config, extCtx, client, serverPath);
 
 	    client.start();
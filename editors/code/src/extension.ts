@@ -1,4 +1,5 @@
 import * as vscode from 'vscode';
+import * as lc from 'vscode-languageclient/node';
 import { Config } from './config';
 import { Context } from './context';
 
@@ -24,7 +25,24 @@ export async function activate(context: vscode.ExtensionContext) {
         }
         await activate(context).catch(console.error);
     });
-	
+
+	ctx.registerCommand("status", (ctx) => async () => {
+		const status = await ctx.client.sendRequest<string>("rust-deadlock-detector/analysisStatus", null);
+		const doc = await vscode.workspace.openTextDocument({ content: status });
+		await vscode.window.showTextDocument(doc);
+	});
+
+	// Run by the critical section lenses, with LSP types as arguments.
+	ctx.registerCommand("showSection", (ctx) => async (uri: string, position: lc.Position, locations: lc.Location[]) => {
+		const converter = ctx.client.protocol2CodeConverter;
+		await vscode.commands.executeCommand(
+			"editor.action.showReferences",
+			converter.asUri(uri),
+			converter.asPosition(position),
+			locations.map((location) => converter.asLocation(location)),
+		);
+	});
+
 }
 
 
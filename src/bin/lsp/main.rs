@@ -1,12 +1,26 @@
-use std::{error::Error, time::Instant, path::PathBuf};
+use std::{error::Error, path::{Path, PathBuf}, env};
 
+use crossbeam_channel::select;
 use lsp_types::{
-    request::DocumentHighlightRequest, InitializeParams, notification::DidSaveTextDocument,
+    request::{DocumentHighlightRequest, HoverRequest, CodeLensRequest, ExecuteCommand, CallHierarchyPrepare, CallHierarchyIncomingCalls, CallHierarchyOutgoingCalls, CodeActionRequest}, InitializeParams, MessageType, notification::{DidSaveTextDocument, DidChangeWorkspaceFolders, DidChangeConfiguration, WorkDoneProgressCancel},
 };
 
-use lsp_server::{Connection, Message};
-use deadlock_lsp::{lsp::{global_ctxt, get_capabilities, cast_notification, cast_request}, utils::{run_analysis_in_dir, cargo_clean}};
+use lsp_server::{Connection, Message, ExtractError};
+use deadlock_lsp::{config::Config, doctor::{self, run_checks}, fingerprint::affects_analysis, lsp::{global_ctxt, code_action::CodeActionSupport, get_capabilities, line_index::PositionEncoding, cast_notification, cast_request, ext::AnalysisStatus, lockbud_ty::{json_schema, stream_json_schema}}, worker::{AnalysisWorker, Event}};
 fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
+    match env::args().nth(1).as_deref() {
+        Some("doctor") => doctor(),
+        Some("schema") => {
+            // The formats lockbud has to write its results in.
+            match env::args().nth(2).as_deref() {
+                Some("stream") => println!("{}", stream_json_schema()),
+                _ => println!("{}", json_schema()),
+            }
+            return Ok(());
+        },
+        _ => {},
+    }
+
     // Note that  we must have our logging only write out to stderr.
     eprintln!("starting generic LSP server");
 
@@ -15,8 +29,12 @@ fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
     let (connection, io_threads) = Connection::stdio();
 
     
-    let initialization_params = connection.initialize(get_capabilities())?;
-    main_loop(connection, initialization_params)?;
+    // The capabilities depend on the client's, e.g. for the position encoding.
+    let (id, initialization_params) = connection.initialize_start()?;
+    let position_encoding = PositionEncoding::negotiate(&initialization_params["capabilities"]);
+    eprintln!("position encoding: {}", position_encoding.name());
+    connection.initialize_finish(id, serde_json::json!({ "capabilities": get_capabilities(position_encoding) }))?;
+    main_loop(connection, initialization_params, position_encoding)?;
     io_threads.join()?;
 
     // Shut down gracefully.
@@ -24,94 +42,350 @@ fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
     Ok(())
 }
 
+/// Checks the lockbud setup for the project in the current directory and exits.
+fn doctor() -> ! {
+    let cwd = env::current_dir().ok();
+    let checks = run_checks(cwd.as_deref());
+    for check in &checks {
+        println!("{}", check);
+    }
+    let failed = checks.iter().filter(|c| c.problem.is_some()).count();
+    if failed > 0 {
+        println!("{} problem(s) found", failed);
+        std::process::exit(1);
+    }
+    println!("no problem found");
+    std::process::exit(0);
+}
+
 fn main_loop(
     connection: Connection,
     params: serde_json::Value,
+    position_encoding: PositionEncoding,
 ) -> Result<(), Box<dyn Error + Sync + Send>> {
     let _params: InitializeParams = serde_json::from_value(params).unwrap();
     let mut ctx = global_ctxt::GlobalCtxt::new(connection.sender.clone());
+    ctx.position_encoding = position_encoding;
+    ctx.supports_progress = _params.capabilities.window.as_ref()
+        .and_then(|window| window.work_done_progress)
+        .unwrap_or(false);
+    ctx.supports_code_lens_refresh = _params.capabilities.workspace.as_ref()
+        .and_then(|workspace| workspace.code_lens.as_ref())
+        .and_then(|code_lens| code_lens.refresh_support)
+        .unwrap_or(false);
+    ctx.code_action_support = CodeActionSupport::new(&_params.capabilities);
     if let Some(client_info) = _params.client_info {
         eprintln!("client_info: {:?}", client_info);
     }
 
-    let workspace_roots: Vec<PathBuf> = _params.workspace_folders.iter()
-    .flat_map(|folders| folders.iter())
-    .flat_map(|folder|folder.uri.to_file_path().ok())
-    .collect();
+    // Clients without workspace folder support only send the root uri.
+    let workspace_roots: Vec<PathBuf> = match _params.workspace_folders {
+        Some(folders) => folders.iter().flat_map(|folder| folder.uri.to_file_path().ok()).collect(),
+        None => _params.root_uri.iter().flat_map(|uri| uri.to_file_path().ok()).collect(),
+    };
 
     eprintln!("workspace roots: {:?}", workspace_roots);
 
-    for workspace in &workspace_roots {
-        let start = Instant::now();
+    let mut config = _params.initialization_options.as_ref()
+        .map(Config::from_section)
+        .unwrap_or_default();
+    eprintln!("config: {:?}", config);
+
+    let mut worker = AnalysisWorker::spawn();
+    worker.configure(config.clone());
+    worker.check(workspace_roots.first().cloned());
 
+    for workspace in workspace_roots {
         eprintln!("init at workspace {:?}", workspace);
+        if ctx.add_workspace(workspace.clone()) {
+            analyze_folder(&ctx, &worker, &workspace);
+        }
+    }
 
-        let analysis_out = workspace.join(".rda/a.json")
-        .to_str().unwrap().to_string();
-        let _ = std::fs::remove_file(&analysis_out);
-        let wsstr = workspace.to_str().unwrap();
-        cargo_clean(wsstr);
-        run_analysis_in_dir(wsstr, &analysis_out);
-        ctx.update_from_json(&analysis_out);
-        ctx.send_diagnoistic();
+    loop {
+        select! {
+            recv(connection.receiver) -> msg => {
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                };
+                match msg {
+                    Message::Request(req) => {
+                        if connection.handle_shutdown(&req)? {
+                            worker.shutdown();
+                            return Ok(());
+                        }
 
-        let elapsed_time = start.elapsed().as_millis();
-        eprintln!("init at workspace {:?} took {}ms", workspace, elapsed_time);
+                        let req = match cast_request::<DocumentHighlightRequest>(req) {
+                            Ok((id, params)) => {
+                                ctx.send_highlight(id, params);
+                                continue;
+                            },
+                            Err(ExtractError::MethodMismatch(req)) => req,
+                            Err(err) => {
+                                eprintln!("parse highlight error: {:?}", err);
+                                continue;
+                            },
+                        };
 
-    }
+                        let req = match cast_request::<HoverRequest>(req) {
+                            Ok((id, params)) => {
+                                ctx.send_hover(id, params);
+                                continue;
+                            },
+                            Err(ExtractError::MethodMismatch(req)) => req,
+                            Err(err) => {
+                                eprintln!("parse hover error: {:?}", err);
+                                continue;
+                            },
+                        };
 
-    for msg in &connection.receiver {
-        // eprintln!("got msg: {:?}", msg);
-        match msg {
-            Message::Request(req) => {
-                if connection.handle_shutdown(&req)? {
-                    return Ok(());
+                        let req = match cast_request::<CallHierarchyPrepare>(req) {
+                            Ok((id, params)) => {
+                                ctx.send_prepare_call_hierarchy(id, params);
+                                continue;
+                            },
+                            Err(ExtractError::MethodMismatch(req)) => req,
+                            Err(err) => {
+                                eprintln!("parse call hierarchy error: {:?}", err);
+                                continue;
+                            },
+                        };
+
+                        let req = match cast_request::<CallHierarchyIncomingCalls>(req) {
+                            Ok((id, params)) => {
+                                ctx.send_incoming_calls(id, params);
+                                continue;
+                            },
+                            Err(ExtractError::MethodMismatch(req)) => req,
+                            Err(err) => {
+                                eprintln!("parse incoming calls error: {:?}", err);
+                                continue;
+                            },
+                        };
+
+                        let req = match cast_request::<CallHierarchyOutgoingCalls>(req) {
+                            Ok((id, params)) => {
+                                ctx.send_outgoing_calls(id, params);
+                                continue;
+                            },
+                            Err(ExtractError::MethodMismatch(req)) => req,
+                            Err(err) => {
+                                eprintln!("parse outgoing calls error: {:?}", err);
+                                continue;
+                            },
+                        };
+
+                        let req = match cast_request::<CodeActionRequest>(req) {
+                            Ok((id, params)) => {
+                                ctx.send_code_actions(id, params);
+                                continue;
+                            },
+                            Err(ExtractError::MethodMismatch(req)) => req,
+                            Err(err) => {
+                                eprintln!("parse code action error: {:?}", err);
+                                continue;
+                            },
+                        };
+
+                        let req = match cast_request::<CodeLensRequest>(req) {
+                            Ok((id, params)) => {
+                                ctx.send_code_lens(id, params);
+                                continue;
+                            },
+                            Err(ExtractError::MethodMismatch(req)) => req,
+                            Err(err) => {
+                                eprintln!("parse code lens error: {:?}", err);
+                                continue;
+                            },
+                        };
+
+                        let req = match cast_request::<ExecuteCommand>(req) {
+                            Ok((id, params)) => {
+                                let result = ctx.reanalyze_root(&params).map(|root| worker.reanalyze(root));
+                                ctx.send_command_result(id, result);
+                                continue;
+                            },
+                            Err(ExtractError::MethodMismatch(req)) => req,
+                            Err(err) => {
+                                eprintln!("parse command error: {:?}", err);
+                                continue;
+                            },
+                        };
+
+                        match cast_request::<AnalysisStatus>(req) {
+                            Ok((id, ())) => {
+                                ctx.send_status(id);
+                            },
+                            Err(err) => {
+                                eprintln!("parse request error: {:?}", err);
+                            },
+                        }
+                        // ...
+                    }
+                    Message::Response(resp) => {
+                        eprintln!("got response: {:?}", resp);
+                    }
+                    Message::Notification(not) => {
+                        eprintln!("got notification: {:?}", not);
+                        let not = match cast_notification::<DidSaveTextDocument>(not) {
+                            Ok(params) => {
+                                eprintln!("{:?} saved!", params.text_document);
+                                let file = params.text_document.uri.to_file_path().ok();
+                                // Unchanged Rust files are caught by the worker's fingerprint.
+                                match &file {
+                                    Some(file) if affects_analysis(file) => {},
+                                    _ => continue,
+                                }
+                                match file.and_then(|file| ctx.analysis_root_for(&file)) {
+                                    Some(root) => worker.analyze(root),
+                                    None => eprintln!("{} is not in any workspace folder", params.text_document.uri),
+                                }
+                                continue;
+                            },
+                            Err(ExtractError::MethodMismatch(not)) => not,
+                            Err(err) => {
+                                eprintln!("parse notification error: {:?}", err);
+                                continue;
+                            }
+                        };
+
+                        let not = match cast_notification::<WorkDoneProgressCancel>(not) {
+                            Ok(params) => {
+                                match ctx.workspace_for_progress(&params.token) {
+                                    Some(root) => worker.cancel(root),
+                                    None => eprintln!("no analysis for progress token {:?}", params.token),
+                                }
+                                continue;
+                            },
+                            Err(ExtractError::MethodMismatch(not)) => not,
+                            Err(err) => {
+                                eprintln!("parse notification error: {:?}", err);
+                                continue;
+                            }
+                        };
+
+                        let not = match cast_notification::<DidChangeConfiguration>(not) {
+                            Ok(params) => {
+                                match Config::from_settings(&params.settings) {
+                                    Some(new_config) if new_config != config => {
+                                        eprintln!("config changed: {:?}", new_config);
+                                        config = new_config;
+                                        worker.configure(config.clone());
+                                        // The roots analyzed so far, so that their findings follow the new settings.
+                                        let analyzed = ctx.analyzed_roots();
+                                        for root in &analyzed {
+                                            worker.analyze(root.clone());
+                                        }
+                                        for folder in &ctx.workspace_roots {
+                                            if !analyzed.iter().any(|root| root.starts_with(folder)) {
+                                                analyze_folder(&ctx, &worker, folder);
+                                            }
+                                        }
+                                    },
+                                    _ => {},
+                                }
+                                continue;
+                            },
+                            Err(ExtractError::MethodMismatch(not)) => not,
+                            Err(err) => {
+                                eprintln!("parse notification error: {:?}", err);
+                                continue;
+                            }
+                        };
+
+                        match cast_notification::<DidChangeWorkspaceFolders>(not) {
+                            Ok(params) => {
+                                for folder in params.event.removed {
+                                    if let Ok(root) = folder.uri.to_file_path() {
+                                        eprintln!("remove workspace {:?}", root);
+                                        worker.cancel(root.clone());
+                                        ctx.remove_workspace(&root);
+                                    }
+                                }
+                                ctx.send_diagnoistic();
+                                for folder in params.event.added {
+                                    if let Ok(root) = folder.uri.to_file_path() {
+                                        eprintln!("add workspace {:?}", root);
+                                        if ctx.add_workspace(root.clone()) {
+                                            analyze_folder(&ctx, &worker, &root);
+                                        }
+                                    }
+                                }
+                            },
+                            Err(ExtractError::MethodMismatch(_)) => {},
+                            Err(err) => {
+                                eprintln!("parse notification error: {:?}", err);
+                            }
+                        }
+                    }
                 }
-                
-                match cast_request::<DocumentHighlightRequest>(req) {
-                    Ok((id, params)) => {
-                        ctx.send_highlight(id, params);
+            }
+            recv(worker.receiver) -> event => {
+                match event {
+                    Ok(Event::Started { workspace }) => {
+                        ctx.begin_progress(&workspace);
                     },
-                    Err(err) => {
-                        eprintln!("parse highlight error: {:?}", err);
+                    Ok(Event::Progress { workspace, message }) => {
+                        ctx.report_progress(&workspace, message);
                     },
-                }
-                // ...
-            }
-            Message::Response(resp) => {
-                eprintln!("got response: {:?}", resp);
-            }
-            Message::Notification(not) => {
-                eprintln!("got notification: {:?}", not);
-                match cast_notification::<DidSaveTextDocument>(not.clone()) {
-                    Ok(params) => {
-                        eprintln!("{:?} saved!", params.text_document);
-                        eprintln!("connection.sender {:?}", connection.sender.len());
-                        match &_params.workspace_folders {
-                            Some(workspaces) => {
-                                let start = Instant::now();
-
-                                let ws = &workspaces[0];
-                                let analysis_out = ws.uri.to_file_path().unwrap().join(".rda/a.json")
-                                .to_str().unwrap().to_string();
-                                let _ = std::fs::remove_file(&analysis_out);
-                                run_analysis_in_dir(ws.uri.to_file_path().unwrap().to_str().unwrap(), &analysis_out);
-                                ctx.update_from_json(&analysis_out);
-                                ctx.send_diagnoistic();
-
-                                let elapsed_time = start.elapsed().as_millis();
-                                eprintln!("DidSaveTextDocument took {}ms", elapsed_time);
-
+                    Ok(Event::Partial { workspace, .. }) if !ctx.is_tracked(&workspace) => {},
+                    Ok(Event::Partial { workspace, crates, result }) => {
+                        ctx.update_from_analysis_result(workspace.clone(), *result);
+                        ctx.send_diagnoistic();
+                        ctx.report_progress(&workspace, format!("{} analyzed", crates.join(", ")));
+                    },
+                    Ok(Event::Checked { checks }) => {
+                        for check in &checks {
+                            eprintln!("{}", check);
+                        }
+                        if let Some(summary) = doctor::summary(&checks) {
+                            ctx.send_message(MessageType::WARNING, summary);
+                        }
+                    },
+                    Ok(Event::Cancelled { workspace }) => {
+                        eprintln!("analysis at workspace {:?} cancelled", workspace);
+                        ctx.end_progress(&workspace, "cancelled".to_string());
+                    },
+                    Ok(Event::Finished { workspace, .. }) if !ctx.is_tracked(&workspace) => {
+                        eprintln!("drop analysis of removed workspace {:?}", workspace);
+                        ctx.end_progress(&workspace, "cancelled".to_string());
+                    },
+                    Ok(Event::Finished { workspace, result, build, elapsed, cached }) => {
+                        ctx.update_build_output(workspace.clone(), &build);
+                        match result {
+                            Ok(result) => {
+                                ctx.update_from_analysis_result(workspace.clone(), *result);
+                                let summary = ctx.findings_summary(&workspace);
+                                ctx.end_progress(&workspace, summary);
+                            },
+                            Err(err) => {
+                                eprintln!("update analysis result: {}", err);
+                                // Also drops findings streamed before the failure.
+                                ctx.remove_result(&workspace);
+                                ctx.send_message(MessageType::ERROR, build.failure_summary(&workspace, &err));
+                                ctx.end_progress(&workspace, "failed".to_string());
                             },
-                            None => {},
                         }
-                        
+                        ctx.send_diagnoistic();
+                        eprintln!("analysis at workspace {:?} took {}ms{}", workspace, elapsed.as_millis(), if cached { " (cached)" } else { "" });
                     },
-                    Err(_) => {}
+                    Err(_) => break,
                 }
             }
         }
     }
+    worker.shutdown();
     Ok(())
 }
 
+/// Analyzes the packages and workspaces in the workspace folder `folder`.
+fn analyze_folder(ctx: &global_ctxt::GlobalCtxt, worker: &AnalysisWorker, folder: &Path) {
+    let roots = ctx.analysis_roots_in(folder);
+    if roots.is_empty() {
+        eprintln!("no Cargo.toml in workspace {:?}", folder);
+    }
+    for root in roots {
+        worker.analyze(root);
+    }
+}
@@ -0,0 +1,228 @@
+use std::{error::Error, path::{Path, PathBuf}};
+
+use serde::Deserialize;
+
+/// One line of `cargo build --message-format=json` output. Only the fields
+/// the server uses are deserialized, all other reasons are skipped.
+#[derive(Debug, Deserialize)]
+struct CargoMessage {
+    reason: String,
+    message: Option<CompilerMessage>,
+    target: Option<CargoTarget>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CargoTarget {
+    name: String,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct CompilerMessage {
+    pub message: String,
+    pub level: String,
+    pub code: Option<CompilerCode>,
+    pub rendered: Option<String>,
+    #[serde(default)]
+    pub spans: Vec<CompilerSpan>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct CompilerCode {
+    pub code: String,
+}
+
+/// Lines and columns are 1 based, `file_name` is relative to the workspace root.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct CompilerSpan {
+    pub file_name: String,
+    pub line_start: u32,
+    pub line_end: u32,
+    pub column_start: u32,
+    pub column_end: u32,
+    pub is_primary: bool,
+}
+
+impl CompilerMessage {
+    pub fn is_error(&self) -> bool {
+        self.level.starts_with("error")
+    }
+
+    pub fn primary_span(&self) -> Option<&CompilerSpan> {
+        self.spans.iter().find(|s| s.is_primary)
+    }
+}
+
+/// Output captured from one analysis build.
+#[derive(Debug, Default, Clone)]
+pub struct BuildOutput {
+    pub messages: Vec<CompilerMessage>,
+    pub stderr: Vec<String>,
+    pub log_path: Option<PathBuf>,
+    /// The crate cargo last reported about.
+    pub current_crate: Option<String>,
+    pub crates_built: usize,
+}
+
+impl BuildOutput {
+    /// Records a line of cargo's stdout, keeping compiler messages.
+    /// Returns true if the line changed the build progress.
+    pub fn push_stdout(&mut self, line: &str) -> bool {
+        let msg = match serde_json::from_str::<CargoMessage>(line) {
+            Ok(msg) => msg,
+            Err(err) => {
+                eprintln!("parse cargo message {:?}: {}", line, err);
+                return false;
+            },
+        };
+
+        if msg.reason == "compiler-artifact" {
+            self.crates_built += 1;
+        }
+        if let Some(message) = msg.message {
+            if msg.reason == "compiler-message" {
+                self.messages.push(message);
+            }
+        }
+        match msg.target {
+            Some(target) if msg.reason == "compiler-artifact" || msg.reason == "compiler-message" => {
+                let changed = self.current_crate.as_ref() != Some(&target.name) || msg.reason == "compiler-artifact";
+                self.current_crate = Some(target.name);
+                changed
+            },
+            _ => false,
+        }
+    }
+
+    /// A short description of what cargo is working on, for progress reports.
+    pub fn progress_message(&self) -> Option<String> {
+        self.current_crate.as_ref().map(|name| format!("{} ({} crates built)", name, self.crates_built))
+    }
+
+    /// Records a line of cargo's stderr. Cargo reports the crate it starts
+    /// compiling there, returns true if that changed the build progress.
+    pub fn push_stderr(&mut self, line: &str) -> bool {
+        self.stderr.push(line.to_string());
+        match line.trim_start().strip_prefix("Compiling ") {
+            Some(rest) => {
+                let name = rest.split_whitespace().next().unwrap_or(rest);
+                let changed = self.current_crate.as_deref() != Some(name);
+                self.current_crate = Some(name.to_string());
+                changed
+            },
+            None => false,
+        }
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &CompilerMessage> {
+        self.messages.iter().filter(|m| m.is_error())
+    }
+
+    /// Writes the rendered compiler messages followed by cargo's stderr to `path`.
+    pub fn write_log(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut log = String::new();
+        for m in &self.messages {
+            log.push_str(m.rendered.as_deref().unwrap_or(&m.message));
+            log.push('\n');
+        }
+        for line in &self.stderr {
+            log.push_str(line);
+            log.push('\n');
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, log)?;
+        self.log_path = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    /// A one line explanation of why the analysis of `workspace` failed.
+    pub fn failure_summary(&self, workspace: &Path, reason: &str) -> String {
+        let mut summary = format!("Deadlock analysis of {} failed: {}.", workspace.display(), reason);
+
+        let errors: Vec<&CompilerMessage> = self.errors().collect();
+        match errors.first() {
+            Some(first) => {
+                summary.push_str(&format!(" {} compile error(s), first: {}.", errors.len(), first.message));
+            },
+            None => {
+                // Without compiler errors the cause is usually the last thing lockbud or cargo printed.
+                if let Some(line) = self.stderr.iter().rev().find(|l| !l.trim().is_empty()) {
+                    summary.push_str(&format!(" {}", line.trim()));
+                }
+            },
+        }
+
+        if let Some(log_path) = &self.log_path {
+            summary.push_str(&format!(" Full log: {}", log_path.display()));
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    const ERROR_MESSAGE: &str = r#"{"reason":"compiler-message","package_id":"foo 0.1.0","target":{"name":"foo"},"message":{"message":"cannot find value `x` in this scope","code":{"code":"E0425","explanation":null},"level":"error","spans":[{"file_name":"src/main.rs","byte_start":16,"byte_end":17,"line_start":2,"line_end":2,"column_start":5,"column_end":6,"is_primary":true,"text":[],"label":"not found in this scope","suggested_replacement":null,"suggestion_applicability":null,"expansion":null}],"children":[],"rendered":"error[E0425]: cannot find value `x` in this scope\n"}}"#;
+    const WARNING_MESSAGE: &str = r#"{"reason":"compiler-message","package_id":"foo 0.1.0","target":{"name":"foo"},"message":{"message":"unused variable: `y`","code":null,"level":"warning","spans":[],"children":[],"rendered":"warning: unused variable: `y`\n"}}"#;
+    const ARTIFACT: &str = r#"{"reason":"compiler-artifact","package_id":"foo 0.1.0","target":{"name":"foo"},"filenames":[]}"#;
+
+    #[test]
+    fn test_build_output_keeps_compiler_messages() {
+        let mut output = BuildOutput::default();
+        output.push_stdout(ERROR_MESSAGE);
+        output.push_stdout(WARNING_MESSAGE);
+        output.push_stdout(ARTIFACT);
+        output.push_stdout("not json");
+
+        assert_eq!(output.messages.len(), 2);
+        assert_eq!(output.crates_built, 1);
+        assert_eq!(output.progress_message(), Some("foo (1 crates built)".to_string()));
+        let errors: Vec<_> = output.errors().collect();
+        assert_eq!(errors.len(), 1);
+
+        let span = errors[0].primary_span().unwrap();
+        assert_eq!(span.file_name, "src/main.rs");
+        assert_eq!(span.line_start, 2);
+        assert_eq!(errors[0].code.as_ref().unwrap().code, "E0425");
+    }
+
+    #[test]
+    fn test_build_output_failure_summary() -> Result<(), Box<dyn Error>> {
+        let mut output = BuildOutput::default();
+        output.push_stdout(ERROR_MESSAGE);
+        output.push_stderr("error: could not compile `foo`");
+        output.write_log(Path::new(".tmp/_test_build.log"))?;
+
+        let log = fs::read_to_string(".tmp/_test_build.log")?;
+        assert!(log.starts_with("error[E0425]"));
+        assert!(log.contains("could not compile"));
+
+        let summary = output.failure_summary(Path::new("/ws"), "cargo exited with code 101");
+        assert!(summary.contains("/ws failed: cargo exited with code 101"));
+        assert!(summary.contains("1 compile error(s), first: cannot find value `x` in this scope"));
+        assert!(summary.ends_with("Full log: .tmp/_test_build.log"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_output_failure_summary_uses_stderr() {
+        let mut output = BuildOutput::default();
+        output.push_stderr("thread 'rustc' panicked at 'lockbud exploded'");
+        output.push_stderr("");
+
+        let summary = output.failure_summary(Path::new("/ws"), "cargo exited with code 101");
+        assert!(summary.ends_with("thread 'rustc' panicked at 'lockbud exploded'"));
+    }
+
+    #[test]
+    fn test_build_output_tracks_compiling_crate() {
+        let mut output = BuildOutput::default();
+        assert!(output.push_stderr("   Compiling my-core v0.1.0 (/ws/core)"));
+        assert!(!output.push_stderr("warning: unused variable"));
+        assert_eq!(output.current_crate.as_deref(), Some("my-core"));
+    }
+}
@@ -0,0 +1,174 @@
+use std::{collections::HashMap, process::Command};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+/// The section clients send settings under, in `initializationOptions` or
+/// nested in `workspace/didChangeConfiguration` settings.
+pub const SETTINGS_SECTION: &str = "rust-deadlock-detector";
+
+/// Server settings, see `contributes.configuration` of the VS Code extension.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Config {
+    pub cargo: CargoConfig,
+    /// Wall-clock limit of one analysis run in seconds, 0 disables it.
+    pub timeout_secs: u64,
+    /// Limit on the resident memory of the analysis process tree in MiB, 0 disables it.
+    pub memory_limit_mb: u64,
+}
+
+/// Flags for the analysis `cargo build`, so that the analyzed code paths match
+/// what the project ships.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CargoConfig {
+    pub features: Vec<String>,
+    pub all_features: bool,
+    pub no_default_features: bool,
+    pub all_targets: bool,
+    pub tests: bool,
+    pub bins: Vec<String>,
+    pub release: bool,
+    pub extra_args: Vec<String>,
+    /// Environment variables passed to cargo and the lockbud wrapper.
+    pub extra_env: HashMap<String, String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            cargo: CargoConfig::default(),
+            timeout_secs: 15 * 60,
+            memory_limit_mb: 0,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the settings section, falling back to defaults on malformed settings.
+    pub fn from_section(section: &Value) -> Config {
+        match serde_json::from_value(section.clone()) {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("invalid {} settings {}: {}", SETTINGS_SECTION, section, err);
+                Config::default()
+            },
+        }
+    }
+
+    /// Reads `workspace/didChangeConfiguration` settings, which hold the section by name.
+    pub fn from_settings(settings: &Value) -> Option<Config> {
+        settings.get(SETTINGS_SECTION).map(Config::from_section)
+    }
+}
+
+impl CargoConfig {
+    pub fn build_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if !self.features.is_empty() {
+            args.push("--features".to_string());
+            args.push(self.features.join(","));
+        }
+        if self.all_features {
+            args.push("--all-features".to_string());
+        }
+        if self.no_default_features {
+            args.push("--no-default-features".to_string());
+        }
+        if self.all_targets {
+            args.push("--all-targets".to_string());
+        }
+        if self.tests {
+            args.push("--tests".to_string());
+        }
+        for bin in &self.bins {
+            args.push("--bin".to_string());
+            args.push(bin.clone());
+        }
+        args.extend(self.profile_args());
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+
+    /// Arguments selecting the build profile, shared by `cargo build` and `cargo clean`.
+    pub fn profile_args(&self) -> Vec<String> {
+        if self.release {
+            vec!["--release".to_string()]
+        } else {
+            Vec::new()
+        }
+    }
+
+    pub fn apply_env(&self, cmd: &mut Command) {
+        cmd.envs(&self.extra_env);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_config_from_section() {
+        let config = Config::from_section(&json!({
+            "serverPath": "/bin/deadlock-lsp",
+            "cargo": {
+                "features": ["tokio", "serde"],
+                "noDefaultFeatures": true,
+                "allTargets": true,
+                "bins": ["server"],
+                "release": true,
+                "extraArgs": ["--locked"],
+                "extraEnv": { "LOCKBUD_LOG": "debug" }
+            }
+        }));
+
+        assert_eq!(
+            config.cargo.build_args(),
+            vec![
+                "--features", "tokio,serde", "--no-default-features", "--all-targets",
+                "--bin", "server", "--release", "--locked",
+            ]
+        );
+        assert_eq!(config.cargo.extra_env.get("LOCKBUD_LOG").unwrap(), "debug");
+    }
+
+    #[test]
+    fn test_config_from_section_should_fallback() {
+        assert_eq!(Config::from_section(&json!(null)), Config::default());
+        assert_eq!(Config::from_section(&json!({ "cargo": { "features": "tokio" } })), Config::default());
+        assert!(Config::default().cargo.build_args().is_empty());
+    }
+
+    #[test]
+    fn test_config_limits() {
+        let config = Config::from_section(&json!({ "timeoutSecs": 60, "memoryLimitMb": 4096 }));
+        assert_eq!(config.timeout_secs, 60);
+        assert_eq!(config.memory_limit_mb, 4096);
+
+        let config = Config::from_section(&json!({ "cargo": {} }));
+        assert_eq!(config.timeout_secs, 900);
+        assert_eq!(config.memory_limit_mb, 0);
+    }
+
+    #[test]
+    fn test_config_from_settings() {
+        let config = Config::from_settings(&json!({ "rust-deadlock-detector": { "cargo": { "tests": true } } }));
+        assert_eq!(config.unwrap().cargo.build_args(), vec!["--tests"]);
+        assert!(Config::from_settings(&json!({ "other": {} })).is_none());
+    }
+
+    #[test]
+    fn test_cargo_config_apply_env() {
+        let mut config = CargoConfig::default();
+        config.extra_env.insert("FOO".to_string(), "bar".to_string());
+
+        let mut cmd = Command::new("cargo");
+        config.apply_env(&mut cmd);
+        let res = cmd.get_envs().find(|x| x.0 == "FOO");
+        assert_eq!(res.unwrap().1.unwrap().to_str().unwrap(), "bar");
+    }
+}
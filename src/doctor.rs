@@ -0,0 +1,279 @@
+use std::{env, ffi::OsString, fmt, fs, path::Path, process::{Command, Output}};
+
+/// The nightly lockbud is built against, see README.
+pub const LOCKBUD_TOOLCHAIN: &str = "nightly-2022-06-14";
+/// Commit hash in `rustc -V` of `LOCKBUD_TOOLCHAIN`.
+pub const LOCKBUD_RUSTC_COMMIT: &str = "1f34da9ec";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub message: String,
+    pub fix: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub problem: Option<Problem>,
+}
+
+impl Problem {
+    fn new(message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Problem { message: message.into(), fix: fix.into() }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.problem {
+            None => write!(f, "[ok]   {}", self.name),
+            Some(problem) => write!(f, "[fail] {}: {}\n       fix: {}", self.name, problem.message, problem.fix),
+        }
+    }
+}
+
+/// One line for the client when checks failed, pointing at `deadlock-lsp doctor` for the fixes.
+pub fn summary(checks: &[Check]) -> Option<String> {
+    let failed: Vec<&str> = checks.iter().filter(|c| c.problem.is_some()).map(|c| c.name).collect();
+    if failed.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "lockbud setup: {} check(s) failed ({}), run `deadlock-lsp doctor` in the project for fixes",
+        failed.len(),
+        failed.join(", ")
+    ))
+}
+
+fn run(cmd: &mut Command) -> Result<Output, String> {
+    cmd.output().map_err(|err| format!("could not run {:?}: {}", cmd, err))
+}
+
+/// Runs every check. Checks that depend on an earlier failed one are skipped.
+/// `workspace` is used to find the toolchain cargo picks for the analyzed project.
+pub fn run_checks(workspace: Option<&Path>) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let lockbud = env::var_os("__DL_RUSTC").filter(|p| !p.is_empty());
+    checks.push(Check { name: "lockbud configured", problem: check_lockbud_configured(lockbud.as_ref()) });
+    let mut lockbud_runs = false;
+    if let Some(lockbud) = &lockbud {
+        let problem = check_lockbud_runs(Path::new(lockbud));
+        lockbud_runs = Path::new(lockbud).is_file() && problem.is_none();
+        checks.push(Check { name: "lockbud runs", problem });
+    }
+
+    let toolchains = run(Command::new("rustup").args(["toolchain", "list"]));
+    let toolchain_problem = match &toolchains {
+        Ok(output) => check_toolchain_list(&String::from_utf8_lossy(&output.stdout)),
+        Err(err) => Some(Problem::new(err.clone(), "install rustup from https://rustup.rs")),
+    };
+    let has_toolchain = toolchain_problem.is_none();
+    checks.push(Check { name: "nightly toolchain installed", problem: toolchain_problem });
+
+    if has_toolchain {
+        let components = run(Command::new("rustup").args(["component", "list", "--installed", "--toolchain", LOCKBUD_TOOLCHAIN]));
+        checks.push(Check {
+            name: "rustc-dev component installed",
+            problem: match &components {
+                Ok(output) => check_components(&String::from_utf8_lossy(&output.stdout)),
+                Err(err) => Some(Problem::new(err.clone(), format!("rustup component add rustc-dev --toolchain {}", LOCKBUD_TOOLCHAIN))),
+            },
+        });
+    }
+
+    let mut rustc = Command::new("rustc");
+    rustc.arg("-V");
+    if let Some(workspace) = workspace {
+        rustc.current_dir(workspace);
+    }
+    checks.push(Check {
+        name: "project uses the lockbud toolchain",
+        problem: match run(&mut rustc) {
+            Ok(output) => check_rustc_version(&String::from_utf8_lossy(&output.stdout)),
+            Err(err) => Some(Problem::new(err, format!("rustup override set {}", LOCKBUD_TOOLCHAIN))),
+        },
+    });
+
+    // A lockbud that runs has loaded them, wherever they were found.
+    if has_toolchain && !lockbud_runs {
+        let sysroot = run(Command::new("rustc").args([&format!("+{}", LOCKBUD_TOOLCHAIN), "--print", "sysroot"]));
+        checks.push(Check {
+            name: "sysroot libraries found",
+            problem: match &sysroot {
+                Ok(output) => check_sysroot_libs(Path::new(String::from_utf8_lossy(&output.stdout).trim())),
+                Err(err) => Some(Problem::new(err.clone(), format!("rustup toolchain install {}", LOCKBUD_TOOLCHAIN))),
+            },
+        });
+    }
+
+    checks
+}
+
+fn check_lockbud_configured(lockbud: Option<&OsString>) -> Option<Problem> {
+    let lockbud = match lockbud {
+        Some(lockbud) => Path::new(lockbud),
+        None => {
+            return Some(Problem::new(
+                "__DL_RUSTC is not set, the analysis would run a plain cargo build without lockbud",
+                "set rust-deadlock-detector.luckbud to the lockbud executable",
+            ));
+        },
+    };
+    if !lockbud.is_file() {
+        return Some(Problem::new(
+            format!("lockbud executable {} does not exist", lockbud.display()),
+            "build lockbud with `cargo install --path .` and point rust-deadlock-detector.luckbud to it",
+        ));
+    }
+    None
+}
+
+/// Cargo invokes the wrapper as `lockbud rustc -vV` before any build, so do the same.
+fn check_lockbud_runs(lockbud: &Path) -> Option<Problem> {
+    if !lockbud.is_file() {
+        return None;
+    }
+    match run(Command::new(lockbud).args(["rustc", "-vV"])) {
+        Ok(output) => check_lockbud_output(output.status.success(), &String::from_utf8_lossy(&output.stderr)),
+        Err(err) => Some(Problem::new(err, "make sure the lockbud executable has execute permission")),
+    }
+}
+
+fn check_lockbud_output(success: bool, stderr: &str) -> Option<Problem> {
+    if success {
+        return None;
+    }
+    if stderr.contains("librustc_driver") || stderr.contains("error while loading shared libraries") || stderr.contains("Library not loaded") {
+        return Some(Problem::new(
+            format!("lockbud cannot load the nightly's rustc libraries: {}", stderr.trim()),
+            format!(
+                "set rust-deadlock-detector.dyldLibPath to the output of `rustc +{} --print sysroot` followed by /lib",
+                LOCKBUD_TOOLCHAIN
+            ),
+        ));
+    }
+    Some(Problem::new(
+        format!("lockbud failed: {}", stderr.trim()),
+        "reinstall lockbud from its web3 branch, see README",
+    ))
+}
+
+fn check_toolchain_list(output: &str) -> Option<Problem> {
+    if output.lines().any(|l| l.trim_start().starts_with(LOCKBUD_TOOLCHAIN)) {
+        return None;
+    }
+    Some(Problem::new(
+        format!("toolchain {} is not installed", LOCKBUD_TOOLCHAIN),
+        format!("rustup toolchain install {}", LOCKBUD_TOOLCHAIN),
+    ))
+}
+
+fn check_components(output: &str) -> Option<Problem> {
+    if output.lines().any(|l| l.trim_start().starts_with("rustc-dev")) {
+        return None;
+    }
+    Some(Problem::new(
+        format!("component rustc-dev is not installed for {}", LOCKBUD_TOOLCHAIN),
+        format!("rustup component add rustc-dev llvm-tools-preview --toolchain {}", LOCKBUD_TOOLCHAIN),
+    ))
+}
+
+fn check_rustc_version(output: &str) -> Option<Problem> {
+    if output.contains(LOCKBUD_RUSTC_COMMIT) {
+        return None;
+    }
+    Some(Problem::new(
+        format!("the project builds with {}, lockbud expects {}", output.trim(), LOCKBUD_TOOLCHAIN),
+        format!("run `rustup override set {}` in the project or add a rust-toolchain file", LOCKBUD_TOOLCHAIN),
+    ))
+}
+
+/// lockbud links against the `librustc_driver` in `<sysroot>/lib` of its toolchain.
+fn check_sysroot_libs(sysroot: &Path) -> Option<Problem> {
+    let lib = sysroot.join("lib");
+    if has_rustc_driver(&lib) {
+        return None;
+    }
+    Some(Problem::new(
+        format!("librustc_driver was not found in {}", lib.display()),
+        format!("rustup toolchain install --force {}", LOCKBUD_TOOLCHAIN),
+    ))
+}
+
+fn has_rustc_driver(dir: &Path) -> bool {
+    match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .any(|e| e.file_name().to_string_lossy().starts_with("librustc_driver")),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+
+    #[test]
+    fn test_check_lockbud_configured() {
+        assert!(check_lockbud_configured(None).is_some());
+        assert!(check_lockbud_configured(Some(&OsString::from("/not/existed/lockbud"))).is_some());
+    }
+
+    #[test]
+    fn test_check_lockbud_output() {
+        assert!(check_lockbud_output(true, "").is_none());
+
+        let problem = check_lockbud_output(false, "error while loading shared libraries: librustc_driver-abc.so").unwrap();
+        assert!(problem.fix.contains("dyldLibPath"));
+
+        let problem = check_lockbud_output(false, "thread 'main' panicked").unwrap();
+        assert!(problem.message.contains("panicked"));
+    }
+
+    #[test]
+    fn test_check_toolchain_and_components() {
+        assert!(check_toolchain_list("stable-x86_64-unknown-linux-gnu (default)\nnightly-2022-06-14-x86_64-unknown-linux-gnu\n").is_none());
+        assert!(check_toolchain_list("stable-x86_64-unknown-linux-gnu (default)\n").is_some());
+
+        assert!(check_components("cargo-x86_64-unknown-linux-gnu\nrustc-dev-x86_64-unknown-linux-gnu\n").is_none());
+        let problem = check_components("cargo-x86_64-unknown-linux-gnu\n").unwrap();
+        assert!(problem.fix.starts_with("rustup component add rustc-dev"));
+    }
+
+    #[test]
+    fn test_check_rustc_version() {
+        assert!(check_rustc_version("rustc 1.63.0-nightly (1f34da9ec 2022-06-14)").is_none());
+        assert!(check_rustc_version("rustc 1.70.0 (90c541806 2023-05-31)").is_some());
+    }
+
+    #[test]
+    fn test_check_sysroot_libs() -> Result<(), Box<dyn Error>> {
+        let sysroot = Path::new(".tmp/fake_sysroot");
+        fs::create_dir_all(sysroot.join("lib"))?;
+        fs::write(sysroot.join("lib/librustc_driver-1234.so"), "")?;
+
+        assert!(check_sysroot_libs(sysroot).is_none());
+        let problem = check_sysroot_libs(Path::new("/not/existed")).unwrap();
+        assert_eq!(problem.message, "librustc_driver was not found in /not/existed/lib");
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_display() {
+        let ok = Check { name: "lockbud configured", problem: None };
+        assert_eq!(ok.to_string(), "[ok]   lockbud configured");
+
+        let failed = Check { name: "lockbud configured", problem: Some(Problem::new("missing", "install it")) };
+        assert_eq!(failed.to_string(), "[fail] lockbud configured: missing\n       fix: install it");
+
+        assert_eq!(summary(&[ok]), None);
+        let summary = summary(&[failed, Check { name: "lockbud runs", problem: Some(Problem::new("missing", "install it")) }]);
+        assert_eq!(
+            summary.unwrap(),
+            "lockbud setup: 2 check(s) failed (lockbud configured, lockbud runs), run `deadlock-lsp doctor` in the project for fixes"
+        );
+    }
+}
@@ -0,0 +1,225 @@
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::UNIX_EPOCH,
+};
+
+use crate::{config::CargoConfig, project_model::ProjectModel};
+
+/// 64 bit FNV-1a, used instead of `DefaultHasher` because the fingerprint is
+/// stored on disk and must not change between builds of the server.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    /// Writes a length prefixed field, so that adjacent fields cannot run into each other.
+    fn field(&mut self, bytes: &[u8]) {
+        self.write(&(bytes.len() as u64).to_le_bytes());
+        self.write(bytes);
+    }
+}
+
+/// Everything an analysis result depends on: the analyzed crates' sources and
+/// manifests, `Cargo.lock`, the toolchain, the lockbud binary and the build settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint(u64);
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+pub fn fingerprint_path(workspace: &Path) -> PathBuf {
+    workspace.join(".rda/fingerprint")
+}
+
+/// Whether saving `file` can change the analysis result.
+pub fn affects_analysis(file: &Path) -> bool {
+    let name = file.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    name == "Cargo.toml" || name == "Cargo.lock" || matches!(file.extension().and_then(|e| e.to_str()), Some("rs"))
+}
+
+impl Fingerprint {
+    /// `None` if the workspace holds no cargo package, which is then always analyzed.
+    pub fn compute(workspace: &Path, config: &CargoConfig) -> Option<Fingerprint> {
+        let model = ProjectModel::load(workspace).ok()?;
+        let packages = model.packages_in(workspace);
+        if packages.is_empty() {
+            return None;
+        }
+
+        let mut hasher = Fnv::new();
+        let mut files: Vec<PathBuf> = Vec::new();
+        for root in ProjectModel::source_roots(&packages) {
+            collect_sources(&root, &mut files);
+        }
+        files.extend(packages.iter().map(|p| p.manifest_path.clone()));
+        files.push(model.workspace_root.join("Cargo.toml"));
+        files.push(model.workspace_root.join("Cargo.lock"));
+        files.sort();
+        files.dedup();
+        for file in &files {
+            hasher.field(file.to_string_lossy().as_bytes());
+            // A missing file hashes differently from an empty one.
+            match fs::read(file) {
+                Ok(content) => hasher.field(&content),
+                Err(_) => hasher.write(&[0xff]),
+            }
+        }
+
+        hasher.field(&toolchain_version(workspace));
+        hasher.field(lockbud_identity().as_bytes());
+
+        for arg in config.build_args() {
+            hasher.field(arg.as_bytes());
+        }
+        let mut env: Vec<_> = config.extra_env.iter().collect();
+        env.sort();
+        for (key, value) in env {
+            hasher.field(key.as_bytes());
+            hasher.field(value.as_bytes());
+        }
+
+        Some(Fingerprint(hasher.0))
+    }
+
+    pub fn load(workspace: &Path) -> Option<Fingerprint> {
+        let content = fs::read_to_string(fingerprint_path(workspace)).ok()?;
+        u64::from_str_radix(content.trim(), 16).ok().map(Fingerprint)
+    }
+
+    pub fn save(&self, workspace: &Path) -> std::io::Result<()> {
+        let path = fingerprint_path(workspace);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_string())
+    }
+}
+
+/// Rust files under `dir`, skipping hidden directories and build output.
+fn collect_sources(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if path.is_dir() {
+            if !name.starts_with('.') && name != "target" {
+                collect_sources(&path, files);
+            }
+        } else if name.ends_with(".rs") {
+            files.push(path);
+        }
+    }
+}
+
+/// `rustc -vV` as cargo would run it in the workspace, so toolchain overrides count.
+fn toolchain_version(workspace: &Path) -> Vec<u8> {
+    match Command::new("rustc").arg("-vV").current_dir(workspace).output() {
+        Ok(output) => output.stdout,
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Path, size and modification time of the lockbud wrapper.
+fn lockbud_identity() -> String {
+    let lockbud = match env::var_os("__DL_RUSTC") {
+        Some(lockbud) => PathBuf::from(lockbud),
+        None => return String::new(),
+    };
+    let modified = fs::metadata(&lockbud).ok().map(|m| {
+        let mtime = m.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+        (m.len(), mtime.as_nanos())
+    });
+    format!("{} {:?}", lockbud.display(), modified)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+
+    fn fake_crate(name: &str) -> Result<PathBuf, Box<dyn Error>> {
+        let repo = fs::canonicalize(".")?.join(".tmp").join(name);
+        fs::create_dir_all(repo.join("src/nested"))?;
+        fs::create_dir_all(repo.join("target"))?;
+        fs::write(repo.join("Cargo.toml"), format!("[package]\nname = \"{}\"\nversion = \"0.1.0\"\n", name))?;
+        fs::write(repo.join("src/lib.rs"), "mod nested;\n")?;
+        fs::write(repo.join("src/nested/mod.rs"), "")?;
+        Ok(repo)
+    }
+
+    #[test]
+    fn test_fnv_is_stable() {
+        let mut hasher = Fnv::new();
+        hasher.write(b"a");
+        assert_eq!(hasher.0, 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_fingerprint_changes_with_sources() -> Result<(), Box<dyn Error>> {
+        let repo = fake_crate("fake_fingerprint")?;
+        let config = CargoConfig::default();
+
+        let first = Fingerprint::compute(&repo, &config).unwrap();
+        assert_eq!(Fingerprint::compute(&repo, &config), Some(first));
+
+        // Build output is not a source.
+        fs::write(repo.join("target/build.rs"), "fn main() {}")?;
+        assert_eq!(Fingerprint::compute(&repo, &config), Some(first));
+
+        fs::write(repo.join("src/nested/mod.rs"), "fn f() {}")?;
+        let second = Fingerprint::compute(&repo, &config).unwrap();
+        assert_ne!(first, second);
+
+        let release = CargoConfig { release: true, ..CargoConfig::default() };
+        assert_ne!(Fingerprint::compute(&repo, &release), Some(second));
+
+        fs::write(repo.join("src/nested/mod.rs"), "")?;
+        assert_eq!(Fingerprint::compute(&repo, &config), Some(first));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprint_save_and_load() -> Result<(), Box<dyn Error>> {
+        let repo = fs::canonicalize(".")?.join(".tmp/fake_fingerprint_saved");
+        let _ = fs::remove_dir_all(&repo);
+        assert_eq!(Fingerprint::load(&repo), None);
+
+        Fingerprint(0x1234).save(&repo)?;
+        assert_eq!(fs::read_to_string(fingerprint_path(&repo))?, "0000000000001234");
+        assert_eq!(Fingerprint::load(&repo), Some(Fingerprint(0x1234)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_affects_analysis() {
+        assert!(affects_analysis(Path::new("/ws/src/lib.rs")));
+        assert!(affects_analysis(Path::new("/ws/Cargo.toml")));
+        assert!(affects_analysis(Path::new("/ws/Cargo.lock")));
+        assert!(!affects_analysis(Path::new("/ws/README.md")));
+        assert!(!affects_analysis(Path::new("/ws/rs")));
+    }
+
+    #[test]
+    fn test_fingerprint_requires_cargo_project() {
+        assert_eq!(Fingerprint::compute(Path::new("/"), &CargoConfig::default()), None);
+    }
+}
@@ -1,5 +1,14 @@
 #![feature(rustc_private)]
 #![feature(box_patterns)]
 
+pub mod cargo_output;
+pub mod config;
+pub mod doctor;
+pub mod fingerprint;
 pub mod utils;
-pub mod lsp;
\ No newline at end of file
+pub mod lsp;
+pub mod process;
+pub mod project_model;
+pub mod provenance;
+pub mod result_stream;
+pub mod worker;
\ No newline at end of file
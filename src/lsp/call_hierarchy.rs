@@ -0,0 +1,241 @@
+//! Call hierarchy over the call chains of suspicious calls, from where a lock is
+//! acquired, through every frame, down to the call that blocks.
+
+use lsp_types::{CallHierarchyIncomingCall, CallHierarchyItem, CallHierarchyOutgoingCall, Position, Range, SymbolKind};
+use serde::{Deserialize, Serialize};
+
+use super::{line_index::{contains, PositionConverter}, lockbud_ty::{AnalysisResult, LockInfo, RangeInFile, SuspiciousCall}};
+
+/// What a call hierarchy item stands for, kept in its `data` between requests.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "node", rename_all = "snake_case")]
+pub enum Node {
+    /// Where a lock is acquired. It calls the first frame of the chains found while it is held.
+    Lock { at: RangeInFile },
+    /// A frame of a call chain, the call site of the next frame.
+    Frame { at: RangeInFile },
+}
+
+impl Node {
+    fn at(&self) -> &RangeInFile {
+        match self {
+            Node::Lock { at } | Node::Frame { at } => at,
+        }
+    }
+}
+
+/// A call from one node to the next, made at `site` in the file of `from`.
+struct Edge {
+    from: Node,
+    to: Node,
+    site: RangeInFile,
+}
+
+/// The call chains of analysis results as a graph.
+pub struct CallGraph<'a> {
+    calls: Vec<&'a SuspiciousCall>,
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+}
+
+impl<'a> CallGraph<'a> {
+    pub fn new(results: impl IntoIterator<Item = &'a AnalysisResult>) -> Self {
+        let mut graph = CallGraph { calls: Vec::new(), nodes: Vec::new(), edges: Vec::new() };
+        for call in results.into_iter().flat_map(|result| &result.calls) {
+            graph.calls.push(call);
+            let first = match call.callchains.first() {
+                Some(first) => first,
+                None => continue,
+            };
+            for lock in &call.locks {
+                graph.add(Node::Lock { at: lock.acquired_at.clone() }, Node::Frame { at: first.clone() }, first);
+            }
+            for frame in call.callchains.windows(2) {
+                graph.add(Node::Frame { at: frame[0].clone() }, Node::Frame { at: frame[1].clone() }, &frame[0]);
+            }
+            // A chain of a single frame has no edge.
+            graph.add_node(Node::Frame { at: first.clone() });
+        }
+        graph
+    }
+
+    /// Chains sharing frames, e.g. several calls under one lock, share their edges.
+    fn add(&mut self, from: Node, to: Node, site: &RangeInFile) {
+        let known = self.edges.iter().any(|e| e.from == from && e.to == to && e.site == *site);
+        if !known {
+            self.add_node(from.clone());
+            self.add_node(to.clone());
+            self.edges.push(Edge { from, to, site: site.clone() });
+        }
+    }
+
+    fn add_node(&mut self, node: Node) {
+        if !self.nodes.contains(&node) {
+            self.nodes.push(node);
+        }
+    }
+
+    /// Items for the lock acquisitions and frames at `pos` in `file`.
+    pub fn prepare(&self, file: &str, pos: &Position, converter: &mut PositionConverter) -> Vec<CallHierarchyItem> {
+        let mut items = Vec::new();
+        for node in self.nodes.iter().filter(|node| node.at().file == file) {
+            if contains(&converter.range(node.at()), pos) {
+                items.extend(self.item(node, converter));
+            }
+        }
+        items
+    }
+
+    /// The nodes calling `item`, empty for items not prepared from this graph.
+    pub fn incoming(&self, item: &CallHierarchyItem, converter: &mut PositionConverter) -> Vec<CallHierarchyIncomingCall> {
+        let node = match node_of(item) {
+            Some(node) => node,
+            None => return Vec::new(),
+        };
+        let edges: Vec<&Edge> = self.edges.iter().filter(|e| e.to == node).collect();
+        group(&edges, |e| &e.from)
+            .into_iter()
+            .filter_map(|(from, sites)| {
+                Some(CallHierarchyIncomingCall {
+                    from: self.item(from, converter)?,
+                    from_ranges: sites.into_iter().map(|site| converter.range(site)).collect(),
+                })
+            })
+            .collect()
+    }
+
+    /// The nodes `item` calls, empty for the calls that block.
+    pub fn outgoing(&self, item: &CallHierarchyItem, converter: &mut PositionConverter) -> Vec<CallHierarchyOutgoingCall> {
+        let node = match node_of(item) {
+            Some(node) => node,
+            None => return Vec::new(),
+        };
+        let edges: Vec<&Edge> = self.edges.iter().filter(|e| e.from == node).collect();
+        group(&edges, |e| &e.to)
+            .into_iter()
+            .filter_map(|(to, sites)| {
+                Some(CallHierarchyOutgoingCall {
+                    to: self.item(to, converter)?,
+                    from_ranges: sites.into_iter().map(|site| converter.range(site)).collect(),
+                })
+            })
+            .collect()
+    }
+
+    /// Named after its source. Frames where a call blocks name the kinds of the findings
+    /// as detail.
+    fn item(&self, node: &Node, converter: &mut PositionConverter) -> Option<CallHierarchyItem> {
+        let at = node.at();
+        let uri = lsp_types::Url::from_file_path(&at.file).ok()?;
+        let source = converter.first_line(at).map(|s| s.trim().to_string()).filter(|s| !s.is_empty());
+        let (name, kind, detail) = match node {
+            Node::Lock { .. } => {
+                let lock = self.calls.iter().flat_map(|call| &call.locks).find(|lock| lock.acquired_at == *at);
+                let name = lock.map(LockInfo::describe).or(source).unwrap_or_else(|| "lock".to_string());
+                (name, SymbolKind::VARIABLE, Some("lock acquired".to_string()))
+            },
+            Node::Frame { .. } => {
+                let mut kinds: Vec<&str> = self
+                    .calls
+                    .iter()
+                    .filter(|call| call.callchains.last() == Some(at))
+                    .map(|call| call.ty.name())
+                    .collect();
+                kinds.sort();
+                kinds.dedup();
+                let name = source.unwrap_or_else(|| format!("call at line {}", at.start.line));
+                let detail = match kinds.is_empty() {
+                    true => None,
+                    false => Some(kinds.join(", ")),
+                };
+                (name, SymbolKind::FUNCTION, detail)
+            },
+        };
+        let range: Range = converter.range(at);
+        Some(CallHierarchyItem {
+            name,
+            kind,
+            tags: None,
+            detail,
+            uri,
+            range,
+            selection_range: range,
+            data: serde_json::to_value(node).ok(),
+        })
+    }
+}
+
+fn node_of(item: &CallHierarchyItem) -> Option<Node> {
+    item.data.as_ref().and_then(|data| serde_json::from_value(data.clone()).ok())
+}
+
+/// The call sites of `edges` by the node `key` picks, in the order the nodes are first seen.
+fn group<'e>(edges: &[&'e Edge], key: impl Fn(&'e Edge) -> &'e Node) -> Vec<(&'e Node, Vec<&'e RangeInFile>)> {
+    let mut groups: Vec<(&Node, Vec<&RangeInFile>)> = Vec::new();
+    for edge in edges {
+        let node = key(edge);
+        match groups.iter_mut().find(|(n, _)| *n == node) {
+            Some((_, sites)) => sites.push(&edge.site),
+            None => groups.push((node, vec![&edge.site])),
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lsp::{line_index::PositionEncoding, lockbud_ty::Suspicious};
+
+    use super::*;
+
+    fn call(ty: Suspicious, target_line: u32) -> SuspiciousCall {
+        SuspiciousCall {
+            callchains: vec![
+                RangeInFile::new("/ws/a.rs", 3, 9, 3, 20),
+                RangeInFile::new("/ws/b.rs", 7, 5, 7, 30),
+                RangeInFile::new("/ws/c.rs", target_line, 5, target_line, 30),
+            ],
+            ty,
+            locks: vec![LockInfo {
+                expr: "self.a".to_string(),
+                ty: "std::sync::Mutex<i32>".to_string(),
+                acquired_at: RangeInFile::new("/ws/a.rs", 2, 9, 2, 20),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_call_hierarchy_walks_chains() {
+        let result = AnalysisResult::new(vec![call(Suspicious::ChRecv, 4), call(Suspicious::CondVarWait, 9)], Vec::new());
+        let graph = CallGraph::new([&result]);
+        let mut converter = PositionConverter::new(PositionEncoding::Utf16);
+
+        let locks = graph.prepare("/ws/a.rs", &Position { line: 1, character: 10 }, &mut converter);
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0].name, "`self.a` (std::sync::Mutex<i32>)");
+        assert_eq!(locks[0].kind, SymbolKind::VARIABLE);
+
+        // Down from the lock: the shared frames once, then both blocking calls.
+        let first = graph.outgoing(&locks[0], &mut converter);
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].from_ranges[0].start, Position { line: 2, character: 8 });
+        let second = graph.outgoing(&first[0].to, &mut converter);
+        assert_eq!(second.len(), 1);
+        let targets = graph.outgoing(&second[0].to, &mut converter);
+        let details: Vec<Option<String>> = targets.iter().map(|call| call.to.detail.clone()).collect();
+        assert_eq!(details, vec![Some("ChRecv".to_string()), Some("CondVarWait".to_string())]);
+        // Called from the frame in b.rs.
+        assert_eq!(targets[0].from_ranges[0].start, Position { line: 6, character: 4 });
+        assert!(graph.outgoing(&targets[0].to, &mut converter).is_empty());
+
+        // And back up.
+        let callers = graph.incoming(&targets[1].to, &mut converter);
+        assert_eq!(callers.len(), 1);
+        assert_eq!(callers[0].from.uri.as_str(), "file:///ws/b.rs");
+        let callers = graph.incoming(&first[0].to, &mut converter);
+        assert_eq!(callers[0].from.kind, SymbolKind::VARIABLE);
+        assert!(graph.incoming(&locks[0], &mut converter).is_empty());
+
+        assert!(graph.prepare("/ws/a.rs", &Position { line: 5, character: 0 }, &mut converter).is_empty());
+    }
+}
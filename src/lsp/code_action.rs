@@ -0,0 +1,571 @@
+//! Quick fixes for calls made while a guard is held: drop the guard before the call,
+//! end its scope before the call, or copy the data out of the lock. The source is only
+//! matched line by line, so a fix is offered disabled, with the reason, whenever the
+//! code does not look like the pattern it rewrites.
+
+use std::{collections::HashMap, fs};
+
+use lsp_types::{
+    AnnotatedTextEdit, ChangeAnnotation, ClientCapabilities, CodeAction, CodeActionDisabled, CodeActionKind, Diagnostic, DocumentChanges,
+    OneOf, OptionalVersionedTextDocumentIdentifier, Range, TextDocumentEdit, TextEdit, WorkspaceEdit,
+};
+
+use super::{line_index::PositionConverter, lockbud_ty::{LineCol, LockInfo, RangeInFile, Suspicious, SuspiciousCall}};
+
+/// What the client accepts of code actions.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CodeActionSupport {
+    /// Annotated edits needing confirmation, which clients preview before applying them.
+    pub change_annotations: bool,
+    /// Actions shown but not applicable, used to tell why a fix is not offered.
+    pub disabled: bool,
+}
+
+impl CodeActionSupport {
+    pub fn new(capabilities: &ClientCapabilities) -> Self {
+        let workspace_edit = capabilities.workspace.as_ref().and_then(|workspace| workspace.workspace_edit.as_ref());
+        CodeActionSupport {
+            change_annotations: match workspace_edit {
+                Some(edit) => edit.document_changes == Some(true) && edit.change_annotation_support.is_some(),
+                None => false,
+            },
+            disabled: capabilities.text_document.as_ref()
+                .and_then(|text_document| text_document.code_action.as_ref())
+                .and_then(|code_action| code_action.disabled_support)
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// Kinds that go away once the guard is released before the call.
+pub fn fixable(kind: &Suspicious) -> bool {
+    matches!(
+        kind,
+        Suspicious::DoubleLock
+            | Suspicious::ChSend
+            | Suspicious::ChRecv
+            | Suspicious::CondVarWait
+            | Suspicious::BarrierWait
+            | Suspicious::ThreadJoin
+    )
+}
+
+/// The fixes of `call`, attached to `diagnostic`. They edit the first frame of the call
+/// chain, where the function holding the guard makes the call.
+pub fn quick_fixes(
+    call: &SuspiciousCall,
+    diagnostic: &Diagnostic,
+    support: CodeActionSupport,
+    converter: &mut PositionConverter,
+) -> Vec<CodeAction> {
+    let site = match call.callchains.first() {
+        Some(site) => site,
+        None => return Vec::new(),
+    };
+    let source = fs::read_to_string(&site.file)
+        .map(|text| Source::new(&text))
+        .map_err(|err| format!("{} cannot be read: {}", site.file, err));
+
+    let mut fixes = Vec::new();
+    if call.locks.is_empty() {
+        fixes.push(("Drop the guard before this call".to_string(), Err("lockbud did not report which lock is held".to_string()), None));
+    }
+    for lock in &call.locks {
+        fixes.extend(fixes_for(&source, lock, site));
+    }
+
+    let mut actions: Vec<CodeAction> = Vec::new();
+    for (title, fix, caveat) in fixes {
+        let mut action = CodeAction {
+            title,
+            kind: Some(CodeActionKind::QUICKFIX),
+            diagnostics: Some(vec![diagnostic.clone()]),
+            ..CodeAction::default()
+        };
+        match fix {
+            Ok(edits) => {
+                let edits = edits
+                    .into_iter()
+                    .map(|e| TextEdit {
+                        range: Range { start: converter.position(&site.file, e.start), end: converter.position(&site.file, e.end) },
+                        new_text: e.text,
+                    })
+                    .collect();
+                let description = match caveat {
+                    Some(caveat) => format!("{} {}", call.ty.remediation(), caveat),
+                    None => call.ty.remediation().to_string(),
+                };
+                action.edit = workspace_edit(&site.file, edits, &action.title, &description, support);
+                // The first fix that applies changes the least code, dropping the guard if it can.
+                // One that may not compile is never applied without a look.
+                action.is_preferred = Some(caveat.is_none() && !actions.iter().any(|a| a.edit.is_some()));
+            },
+            Err(reason) if support.disabled => action.disabled = Some(CodeActionDisabled { reason }),
+            Err(reason) => {
+                eprintln!("no quick fix {:?}: {}", action.title, reason);
+                continue;
+            },
+        }
+        actions.push(action);
+    }
+    actions
+}
+
+type Fix = Result<Vec<Edit>, String>;
+
+/// The three fixes for `lock`, each with its edits or why it cannot be applied, and
+/// what has to hold for the edits to compile, beyond what is checked here.
+fn fixes_for(source: &Result<Source, String>, lock: &LockInfo, site: &RangeInFile) -> Vec<(String, Fix, Option<&'static str>)> {
+    let binding = source.as_ref().map_err(String::clone).and_then(|src| {
+        if lock.acquired_at.file != site.file {
+            return Err("the lock is acquired in another file than the call is made from".to_string());
+        }
+        let binding = src.binding(&lock.acquired_at)?;
+        Ok((src, binding))
+    });
+    let site_line = site.start.line.saturating_sub(1) as usize;
+    vec![
+        (
+            format!("Drop the guard of `{}` before this call", lock.expr),
+            binding.clone().and_then(|(src, b)| drop_guard(src, &b, site_line)),
+            None,
+        ),
+        (
+            format!("End the scope of the guard of `{}` before this call", lock.expr),
+            binding.clone().and_then(|(src, b)| scope_guard(src, &b, site_line)),
+            None,
+        ),
+        (
+            format!("Copy the data out of `{}` instead of holding the guard", lock.expr),
+            binding.and_then(|(src, b)| copy_data(src, &b)),
+            Some("The copy compiles only if the data implements Clone."),
+        ),
+    ]
+}
+
+fn workspace_edit(file: &str, edits: Vec<TextEdit>, title: &str, description: &str, support: CodeActionSupport) -> Option<WorkspaceEdit> {
+    let uri = lsp_types::Url::from_file_path(file).ok()?;
+    if !support.change_annotations {
+        return Some(WorkspaceEdit { changes: Some(HashMap::from([(uri, edits)])), ..WorkspaceEdit::default() });
+    }
+    // Needing confirmation makes the client show the edits before applying them.
+    let id = "rust-deadlock-detector/quickFix".to_string();
+    let annotation = ChangeAnnotation { label: title.to_string(), needs_confirmation: Some(true), description: Some(description.to_string()) };
+    Some(WorkspaceEdit {
+        changes: None,
+        document_changes: Some(DocumentChanges::Edits(vec![TextDocumentEdit {
+            text_document: OptionalVersionedTextDocumentIdentifier { uri, version: None },
+            edits: edits.into_iter().map(|text_edit| OneOf::Right(AnnotatedTextEdit { text_edit, annotation_id: id.clone() })).collect(),
+        }])),
+        change_annotations: Some(HashMap::from([(id, annotation)])),
+    })
+}
+
+/// A replacement between two 1 based positions with char columns.
+#[derive(Debug, Clone, PartialEq)]
+struct Edit {
+    start: LineCol,
+    end: LineCol,
+    text: String,
+}
+
+impl Edit {
+    /// Inserts `text` at the 0 based `line` and char `col`.
+    fn insert(line: usize, col: usize, text: String) -> Self {
+        let at = LineCol { line: line as u32 + 1, col: col as u32 + 1 };
+        Edit { start: at, end: at, text }
+    }
+}
+
+/// `let [mut] name [: Type] = <acquisition>;` on a single line.
+#[derive(Debug, Clone)]
+struct Binding {
+    name: String,
+    mutable: bool,
+    typed: bool,
+    /// 0 based line of the `let`.
+    line: usize,
+    /// Char column of the `;` ending the `let`.
+    semicolon: usize,
+}
+
+/// The lines of a file with string and char literals and line comments blanked, so that
+/// the braces and names in them do not count.
+struct Source {
+    lines: Vec<Vec<char>>,
+}
+
+impl Source {
+    fn new(text: &str) -> Self {
+        Source { lines: text.lines().map(code).collect() }
+    }
+
+    fn line(&self, line: usize) -> &[char] {
+        self.lines.get(line).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    fn indent(&self, line: usize) -> String {
+        self.line(line).iter().take_while(|c| c.is_whitespace()).collect()
+    }
+
+    fn binding(&self, acquired_at: &RangeInFile) -> Result<Binding, String> {
+        let line = acquired_at.start.line.saturating_sub(1) as usize;
+        let text = self.line(line);
+        let before: String = text.iter().take(acquired_at.start.col.saturating_sub(1) as usize).collect();
+        let not_bound = || "the guard is not bound with `let` where the lock is acquired".to_string();
+        let rest = before.trim_start().strip_prefix("let ").ok_or_else(not_bound)?;
+        let pattern = &rest[..rest.find('=').ok_or_else(not_bound)?];
+        let (pattern, typed) = match pattern.split_once(':') {
+            Some((pattern, _)) => (pattern, true),
+            None => (pattern, false),
+        };
+        let (name, mutable) = match pattern.trim().strip_prefix("mut ") {
+            Some(name) => (name.trim(), true),
+            None => (pattern.trim(), false),
+        };
+        if name.is_empty() || !name.chars().all(is_ident) {
+            return Err("the guard is bound to a pattern".to_string());
+        }
+        let semicolon = match text.iter().rposition(|c| !c.is_whitespace()) {
+            Some(end) if text[end] == ';' => end,
+            _ => return Err("the `let` of the guard continues on the next line".to_string()),
+        };
+        Ok(Binding { name: name.to_string(), mutable, typed, line, semicolon })
+    }
+
+    /// Brace depth at the start of each line after `b` up to `to`, relative to the block
+    /// `b` is in, or `None` if that block closes before.
+    fn depths(&self, b: &Binding, to: usize) -> Option<Vec<i32>> {
+        let mut depth = 0;
+        let mut depths = Vec::new();
+        for line in b.line..to {
+            let start = if line == b.line { b.semicolon + 1 } else { 0 };
+            for c in self.line(line).iter().skip(start) {
+                match c {
+                    '{' => depth += 1,
+                    '}' => depth -= 1,
+                    _ => {},
+                }
+                if depth < 0 {
+                    return None;
+                }
+            }
+            depths.push(depth);
+        }
+        Some(depths)
+    }
+
+    /// The line of the `}` closing the block `b` is in, the last line if there is none.
+    fn block_end(&self, b: &Binding) -> usize {
+        let mut depth = 0;
+        for line in b.line..self.lines.len() {
+            let start = if line == b.line { b.semicolon + 1 } else { 0 };
+            for c in self.line(line).iter().skip(start) {
+                match c {
+                    '{' => depth += 1,
+                    '}' if depth == 0 => return line,
+                    '}' => depth -= 1,
+                    _ => {},
+                }
+            }
+        }
+        self.lines.len().saturating_sub(1)
+    }
+
+    /// Char columns where `name` is used on `line`, leaving out fields of the same name.
+    fn uses(&self, line: usize, name: &str) -> Vec<usize> {
+        let text = self.line(line);
+        let name: Vec<char> = name.chars().collect();
+        (0..text.len())
+            .filter(|&i| text[i..].starts_with(&name))
+            .filter(|&i| i == 0 || !is_ident(text[i - 1]))
+            .filter(|&i| !matches!(text.get(i + name.len()), Some(c) if is_ident(*c)))
+            .filter(|&i| previous(text, i).map(|p| text[p]) != Some('.'))
+            .collect()
+    }
+}
+
+/// Column of the last non-blank char before `i`.
+fn previous(text: &[char], i: usize) -> Option<usize> {
+    text[..i].iter().rposition(|c| !c.is_whitespace())
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// `line` with the contents of literals and a trailing `//` comment replaced by spaces.
+fn code(line: &str) -> Vec<char> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut code = chars.clone();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '/' if chars.get(i + 1) == Some(&'/') => {
+                code[i..].iter_mut().for_each(|c| *c = ' ');
+                break;
+            },
+            '"' => {
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    let escaped = chars[i] == '\\';
+                    code[i] = ' ';
+                    if escaped && i + 1 < chars.len() {
+                        code[i + 1] = ' ';
+                        i += 1;
+                    }
+                    i += 1;
+                }
+            },
+            // A char literal, not a lifetime.
+            '\'' if chars.get(i + 2) == Some(&'\'') || chars.get(i + 1) == Some(&'\\') => {
+                let mut end = i + 1;
+                while end < chars.len() && chars[end] != '\'' {
+                    // Skips the escaped char, e.g. the quote of '\''.
+                    if chars[end] == '\\' {
+                        end += 1;
+                    }
+                    end += 1;
+                }
+                let end = end.min(chars.len());
+                code[i + 1..end].iter_mut().for_each(|c| *c = ' ');
+                i = end;
+            },
+            _ => {},
+        }
+        i += 1;
+    }
+    code
+}
+
+/// Whether the guard of `b` is still held at the start of `site_line`, directly in its
+/// block, and no longer used from there on, so that releasing it there is safe.
+fn releasable_at(src: &Source, b: &Binding, site_line: usize) -> Result<(), String> {
+    if site_line <= b.line {
+        return Err("the call is made on the line the guard is bound".to_string());
+    }
+    match src.depths(b, site_line).and_then(|depths| depths.last().copied()) {
+        Some(0) => {},
+        Some(_) => return Err("the call is in a block nested in the scope of the guard, e.g. a branch or a loop".to_string()),
+        None => return Err("the scope of the guard ends before the call".to_string()),
+    }
+    let statement_end = (b.line..site_line).rev().find_map(|line| src.line(line).iter().rev().find(|c| !c.is_whitespace()));
+    if !matches!(statement_end, Some(';' | '{' | '}')) {
+        return Err("the call is part of a statement that starts before it".to_string());
+    }
+    if (site_line..=src.block_end(b)).any(|line| !src.uses(line, &b.name).is_empty()) {
+        return Err(format!("`{}` is still used from the call on", b.name));
+    }
+    Ok(())
+}
+
+/// Inserts `drop(guard);` before the line of the call.
+fn drop_guard(src: &Source, b: &Binding, site_line: usize) -> Fix {
+    releasable_at(src, b, site_line)?;
+    Ok(vec![Edit::insert(site_line, 0, format!("{}drop({});\n", src.indent(site_line), b.name))])
+}
+
+/// Wraps the lines from the `let` of the guard to the call in a block.
+fn scope_guard(src: &Source, b: &Binding, site_line: usize) -> Fix {
+    releasable_at(src, b, site_line)?;
+    let depths = src.depths(b, site_line).unwrap_or_default();
+    let scoped_let = (b.line + 1..site_line)
+        .zip(depths)
+        .any(|(line, depth)| depth == 0 && src.line(line).iter().collect::<String>().trim_start().starts_with("let "));
+    if scoped_let {
+        return Err("other bindings made while the guard is held would go out of scope".to_string());
+    }
+
+    let indent = src.indent(b.line);
+    let mut edits = vec![Edit::insert(b.line, 0, format!("{}{{\n    ", indent))];
+    for line in b.line + 1..site_line {
+        if src.line(line).iter().any(|c| !c.is_whitespace()) {
+            edits.push(Edit::insert(line, 0, "    ".to_string()));
+        }
+    }
+    edits.push(Edit::insert(site_line, 0, format!("{}}}\n", indent)));
+    Ok(edits)
+}
+
+/// Clones the data out of the guard, which is then dropped at the end of the `let`, and
+/// reads `*guard` as the copy.
+fn copy_data(src: &Source, b: &Binding) -> Fix {
+    if b.typed {
+        return Err("the type of the guard is written out".to_string());
+    }
+    if b.mutable {
+        return Err(format!("`{}` is `mut`, writes through it would no longer reach the lock", b.name));
+    }
+    let mut edits = vec![Edit::insert(b.line, b.semicolon, ".clone()".to_string())];
+    let mut used = false;
+    for line in b.line + 1..=src.block_end(b) {
+        let text = src.line(line);
+        for i in src.uses(line, &b.name) {
+            used = true;
+            // `*guard`, not a multiplication.
+            let star = previous(text, i).filter(|&p| text[p] == '*');
+            let deref = star.filter(|&p| !matches!(previous(text, p).map(|q| text[q]), Some(c) if is_ident(c) || c == ')' || c == ']'));
+            if let Some(p) = deref {
+                let start = LineCol { line: line as u32 + 1, col: p as u32 + 1 };
+                edits.push(Edit { start, end: LineCol { line: start.line, col: start.col + 1 }, text: String::new() });
+            }
+        }
+    }
+    if !used {
+        return Err(format!("nothing is read from `{}`", b.name));
+    }
+    Ok(edits)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use lsp_types::Position;
+
+    use crate::lsp::line_index::PositionEncoding;
+
+    use super::*;
+
+    const SOURCE: &str = "fn f(&self) {
+    let data = self.data.lock().unwrap(); // locked
+    let n = *data + 1;
+    self.tx.send(n).unwrap();
+    if n > 2 {
+        self.rx.recv().unwrap();
+    }
+}
+";
+
+    fn binding(src: &Source) -> Binding {
+        src.binding(&RangeInFile::new("/a.rs", 2, 16, 2, 32)).unwrap()
+    }
+
+    #[test]
+    fn test_code_blanks_literals_and_comments() {
+        let line: String = code(r#"let s = "{\"}"; let c = '{'; f::<'a>(x) // }"#).into_iter().collect();
+        assert_eq!(line, r#"let s = "    "; let c = ' '; f::<'a>(x)     "#);
+        let line: String = code(r#"let q = '\''; let g = m.lock(); // '"#).into_iter().collect();
+        assert_eq!(line, r#"let q = '  '; let g = m.lock();     "#);
+    }
+
+    #[test]
+    fn test_drop_guard() {
+        let src = Source::new(SOURCE);
+        let b = binding(&src);
+        assert_eq!((b.name.as_str(), b.mutable, b.typed, b.semicolon), ("data", false, false, 40));
+
+        let edits = drop_guard(&src, &b, 3).unwrap();
+        assert_eq!(edits, vec![Edit::insert(3, 0, "    drop(data);\n".to_string())]);
+        assert_eq!(drop_guard(&src, &b, 2).unwrap_err(), "`data` is still used from the call on");
+        assert_eq!(
+            drop_guard(&src, &b, 5).unwrap_err(),
+            "the call is in a block nested in the scope of the guard, e.g. a branch or a loop"
+        );
+    }
+
+    #[test]
+    fn test_scope_guard() {
+        let src = Source::new("fn f(&self) {\n    let g = self.a.lock();\n    g.push(1);\n\n    self.b.lock();\n}\n");
+        let b = src.binding(&RangeInFile::new("/a.rs", 2, 13, 2, 26)).unwrap();
+        assert_eq!(
+            scope_guard(&src, &b, 4).unwrap(),
+            vec![
+                Edit::insert(1, 0, "    {\n    ".to_string()),
+                Edit::insert(2, 0, "    ".to_string()),
+                Edit::insert(4, 0, "    }\n".to_string()),
+            ]
+        );
+
+        let src = Source::new(SOURCE);
+        assert_eq!(
+            scope_guard(&src, &binding(&src), 3).unwrap_err(),
+            "other bindings made while the guard is held would go out of scope"
+        );
+    }
+
+    #[test]
+    fn test_copy_data() {
+        let src = Source::new(SOURCE);
+        let edits = copy_data(&src, &binding(&src)).unwrap();
+        let star = LineCol { line: 3, col: 13 };
+        assert_eq!(
+            edits,
+            vec![
+                Edit::insert(1, 40, ".clone()".to_string()),
+                Edit { start: star, end: LineCol { line: 3, col: 14 }, text: String::new() },
+            ]
+        );
+
+        let src = Source::new("let mut g = m.lock();\n*g += 1;\n");
+        let b = src.binding(&RangeInFile::new("/a.rs", 1, 13, 1, 21)).unwrap();
+        assert_eq!(copy_data(&src, &b).unwrap_err(), "`g` is `mut`, writes through it would no longer reach the lock");
+    }
+
+    #[test]
+    fn test_quick_fixes() -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(".tmp")?;
+        let file = fs::canonicalize(".tmp")?.join("_test_quick_fix.rs");
+        fs::write(&file, SOURCE)?;
+        let file = file.to_string_lossy().to_string();
+        let mut call = SuspiciousCall {
+            callchains: vec![RangeInFile::new(file.clone(), 4, 5, 4, 29)],
+            ty: Suspicious::ChSend,
+            locks: vec![LockInfo {
+                expr: "self.data".to_string(),
+                ty: "std::sync::Mutex<i32>".to_string(),
+                acquired_at: RangeInFile::new(file.clone(), 2, 16, 2, 32),
+            }],
+        };
+        let diagnostic = Diagnostic { message: "ChSend".to_string(), ..Diagnostic::default() };
+        let mut converter = PositionConverter::new(PositionEncoding::Utf16);
+
+        let support = CodeActionSupport { change_annotations: true, disabled: true };
+        let actions = quick_fixes(&call, &diagnostic, support, &mut converter);
+        let titles: Vec<&str> = actions.iter().map(|a| a.title.as_str()).collect();
+        assert_eq!(
+            titles,
+            vec![
+                "Drop the guard of `self.data` before this call",
+                "End the scope of the guard of `self.data` before this call",
+                "Copy the data out of `self.data` instead of holding the guard",
+            ]
+        );
+        assert_eq!(actions[0].is_preferred, Some(true));
+        assert_eq!(actions[0].diagnostics, Some(vec![diagnostic.clone()]));
+        let edit = actions[0].edit.as_ref().unwrap();
+        assert_eq!(edit.change_annotations.as_ref().unwrap()["rust-deadlock-detector/quickFix"].needs_confirmation, Some(true));
+        match &edit.document_changes {
+            Some(DocumentChanges::Edits(edits)) => match &edits[0].edits[0] {
+                OneOf::Right(annotated) => {
+                    assert_eq!(annotated.text_edit.range.start, Position { line: 3, character: 0 });
+                    assert_eq!(annotated.text_edit.new_text, "    drop(data);\n");
+                },
+                edit => panic!("unexpected edit {:?}", edit),
+            },
+            changes => panic!("unexpected changes {:?}", changes),
+        }
+        assert!(actions[1].disabled.is_some());
+        assert!(actions[2].edit.is_some());
+
+        // Clients without disabled actions only get the fixes that apply, as plain edits.
+        let actions = quick_fixes(&call, &diagnostic, CodeActionSupport::default(), &mut converter);
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[1].is_preferred, Some(false));
+        assert_eq!(actions[1].edit.as_ref().unwrap().changes.as_ref().unwrap().values().next().unwrap().len(), 2);
+
+        // With the guard still used at the call, only copying the data applies, but the data
+        // may not be Clone.
+        call.callchains[0] = RangeInFile::new(file, 3, 13, 3, 18);
+        let actions = quick_fixes(&call, &diagnostic, support, &mut converter);
+        let applicable: Vec<&CodeAction> = actions.iter().filter(|a| a.edit.is_some()).collect();
+        assert_eq!(applicable.len(), 1);
+        assert_eq!(applicable[0].title, "Copy the data out of `self.data` instead of holding the guard");
+        assert_eq!(applicable[0].is_preferred, Some(false));
+        let annotations = applicable[0].edit.as_ref().unwrap().change_annotations.as_ref().unwrap();
+        let annotation = &annotations["rust-deadlock-detector/quickFix"];
+        assert_eq!(annotation.needs_confirmation, Some(true));
+        assert!(annotation.description.as_ref().unwrap().ends_with("The copy compiles only if the data implements Clone."));
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+//! Lenses summarizing critical sections, and the commands they run.
+
+use lsp_types::{CodeLens, Command, Location, Position, Range};
+use serde_json::json;
+
+use super::{line_index::PositionConverter, lockbud_ty::{AnalysisResult, HighlightArea, RangeInFile}};
+
+/// Analyzes the crate of the file whose URI is the only argument. Run by the server.
+pub const REANALYZE_COMMAND: &str = "rust-deadlock-detector.reanalyze";
+/// Shows the locations given after a URI and a position, like `editor.action.showReferences`.
+/// Run by the client.
+pub const SHOW_SECTION_COMMAND: &str = "rust-deadlock-detector.showSection";
+
+/// E.g. "critical section · 14 lines · 2 blocking calls".
+pub fn section_title(result: &AnalysisResult, area: &HighlightArea) -> String {
+    format!(
+        "critical section · {} · {}",
+        count(area.lines() as usize, "line"),
+        count(area.calls_inside(&result.calls).len(), "blocking call")
+    )
+}
+
+fn count(n: usize, noun: &str) -> String {
+    match n {
+        1 => format!("1 {}", noun),
+        n => format!("{} {}s", n, noun),
+    }
+}
+
+/// A lens at each trigger of `area` in `file`, showing the section and the calls found in it.
+pub fn section_lenses(file: &str, result: &AnalysisResult, area: &HighlightArea, converter: &mut PositionConverter) -> Vec<CodeLens> {
+    let uri = match lsp_types::Url::from_file_path(file) {
+        Ok(uri) => uri,
+        Err(()) => return Vec::new(),
+    };
+    let targets = area.calls_inside(&result.calls).into_iter().filter_map(|call| call.callchains.last());
+    let locations: Vec<Location> = area
+        .ranges
+        .iter()
+        .chain(targets)
+        .filter_map(|r| location(r, converter))
+        .collect();
+    let title = section_title(result, area);
+    area.triggers
+        .iter()
+        .filter(|t| t.file == file)
+        .map(|trigger| {
+            let range = converter.range(trigger);
+            CodeLens {
+                range,
+                command: Some(Command {
+                    title: title.clone(),
+                    command: SHOW_SECTION_COMMAND.to_string(),
+                    arguments: Some(vec![json!(uri), json!(range.start), json!(locations)]),
+                }),
+                data: None,
+            }
+        })
+        .collect()
+}
+
+/// The lens at the top of a file that reanalyzes its crate, or the whole cargo
+/// workspace if `workspace`, as members are analyzed together.
+pub fn reanalyze_lens(uri: &lsp_types::Url, workspace: bool) -> CodeLens {
+    let title = match workspace {
+        true => "Reanalyze workspace",
+        false => "Reanalyze crate",
+    };
+    CodeLens {
+        range: Range { start: Position::default(), end: Position::default() },
+        command: Some(Command {
+            title: title.to_string(),
+            command: REANALYZE_COMMAND.to_string(),
+            arguments: Some(vec![json!(uri)]),
+        }),
+        data: None,
+    }
+}
+
+fn location(r: &RangeInFile, converter: &mut PositionConverter) -> Option<Location> {
+    let uri = lsp_types::Url::from_file_path(&r.file).ok()?;
+    Some(Location { uri, range: converter.range(r) })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lsp::{line_index::PositionEncoding, lockbud_ty::{SectionKind, Suspicious, SuspiciousCall}};
+
+    use super::*;
+
+    fn call(line: u32) -> SuspiciousCall {
+        SuspiciousCall {
+            callchains: vec![RangeInFile::new("/ws/a.rs", line, 9, line, 20), RangeInFile::new("/ws/b.rs", 7, 5, 7, 30)],
+            ty: Suspicious::ChRecv,
+            locks: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_section_lenses() {
+        let area = HighlightArea {
+            triggers: vec![RangeInFile::new("/ws/a.rs", 2, 9, 2, 20)],
+            ranges: vec![RangeInFile::new("/ws/a.rs", 2, 9, 15, 6)],
+            await_points: Vec::new(),
+            kind: SectionKind::Lock,
+        };
+        let result = AnalysisResult::new(vec![call(3), call(4), call(20)], Vec::new());
+        assert_eq!(section_title(&result, &area), "critical section · 14 lines · 2 blocking calls");
+
+        let mut converter = PositionConverter::new(PositionEncoding::Utf16);
+        let lenses = section_lenses("/ws/a.rs", &result, &area, &mut converter);
+        assert_eq!(lenses.len(), 1);
+        assert_eq!(lenses[0].range.start, Position { line: 1, character: 8 });
+        let command = lenses[0].command.as_ref().unwrap();
+        assert_eq!(command.command, SHOW_SECTION_COMMAND);
+        let args = command.arguments.as_ref().unwrap();
+        assert_eq!(args[0], "file:///ws/a.rs");
+        assert_eq!(args[1], json!({ "line": 1, "character": 8 }));
+        // The section, then where each call inside it blocks.
+        let uris: Vec<&str> = args[2].as_array().unwrap().iter().map(|l| l["uri"].as_str().unwrap()).collect();
+        assert_eq!(uris, vec!["file:///ws/a.rs", "file:///ws/b.rs", "file:///ws/b.rs"]);
+
+        assert!(section_lenses("/ws/b.rs", &result, &area, &mut converter).is_empty());
+
+        let uri = lsp_types::Url::parse("file:///ws/a.rs").unwrap();
+        assert_eq!(reanalyze_lens(&uri, true).command.unwrap().title, "Reanalyze workspace");
+    }
+}
@@ -0,0 +1,12 @@
+//! Requests the server adds to the protocol.
+
+use lsp_types::request::Request;
+
+/// Describes the analysis of every workspace folder as plain text.
+pub enum AnalysisStatus {}
+
+impl Request for AnalysisStatus {
+    type Params = ();
+    type Result = String;
+    const METHOD: &'static str = "rust-deadlock-detector/analysisStatus";
+}
@@ -1,43 +1,114 @@
-use std::{ collections::HashMap};
+use std::{ collections::{HashMap, HashSet}, path::{Path, PathBuf}};
 
-use lsp_types::{ Range, DocumentHighlightKind, Position, DocumentHighlight,DocumentHighlightParams, DiagnosticRelatedInformation, Location, Diagnostic, DiagnosticSeverity, PublishDiagnosticsParams};
+use lsp_types::{ Range, DocumentHighlightKind, Position, DocumentHighlight,DocumentHighlightParams, DiagnosticRelatedInformation, Location, Diagnostic, DiagnosticSeverity, PublishDiagnosticsParams, MessageType, NumberOrString, ProgressToken, ProgressParams, ProgressParamsValue, WorkDoneProgress, WorkDoneProgressBegin, WorkDoneProgressReport, WorkDoneProgressEnd, WorkDoneProgressCreateParams, CodeDescription, Hover, HoverContents, HoverParams, MarkupContent, MarkupKind, CodeLens, CodeLensParams, ExecuteCommandParams, CallHierarchyPrepareParams, CallHierarchyIncomingCallsParams, CallHierarchyOutgoingCallsParams, CodeAction, CodeActionKind, CodeActionParams};
 
 use lsp_server::Message;
 use lsp_server::{RequestId};
 use crossbeam_channel::{Sender};
 
-use super::lockbud_ty::{AnalysisResult, HighlightArea, RangeInFile, SuspiciousCall};
+use crate::{cargo_output::{BuildOutput, CompilerMessage}, utils::{declares_workspace, find_analysis_root, find_analysis_roots}};
+
+use super::{call_hierarchy::CallGraph, code_action::{self, CodeActionSupport}, code_lens::{self, REANALYZE_COMMAND}, hover, line_index::{contains, PositionConverter, PositionEncoding}, lockbud_ty::{AnalysisResult, HighlightArea, LineCol, LockConflict, LockInfo, RangeInFile, Suspicious, SuspiciousCall}};
 
 pub struct DocHighlightsWithTrigger {
     areas: Vec<DocumentHighlight>,
-    triggers: Vec<RangeInFile>
+    triggers: Vec<Range>
 }
 
 type IndexedHighlights = HashMap<String, Vec< DocHighlightsWithTrigger > >;
 type IndexedDiagnostics = HashMap<String, Vec< Diagnostic > >;
 
 pub struct GlobalCtxt {
-    pub result: Option<AnalysisResult>,
+    /// Analysis results keyed by the directory they were produced for.
+    pub results: HashMap<PathBuf, AnalysisResult>,
+    /// Compiler errors of the last analysis build, keyed like `results`.
+    pub build_diagnostics: HashMap<PathBuf, IndexedDiagnostics>,
     pub sender: Sender<Message>,
-    pub file_highlights: IndexedHighlights
+    pub file_highlights: IndexedHighlights,
+    /// Lenses of the critical sections, keyed like `file_highlights`.
+    pub file_lenses: HashMap<String, Vec<CodeLens>>,
+    pub workspace_roots: Vec<PathBuf>,
+    /// Whether the client accepts `window/workDoneProgress/create`.
+    pub supports_progress: bool,
+    /// Whether the client accepts `workspace/codeLens/refresh`.
+    pub supports_code_lens_refresh: bool,
+    pub code_action_support: CodeActionSupport,
+    /// Unit of the columns of positions exchanged with the client.
+    pub position_encoding: PositionEncoding,
+    /// Files diagnostics were last published for, cleared once their findings are gone.
+    published: HashSet<lsp_types::Url>,
+    progress_tokens: HashMap<PathBuf, ProgressToken>,
+    next_request_id: i32
 }
 
 
 impl GlobalCtxt {
     pub fn new(sender: Sender<Message>) -> Self {
         Self {
-            result: None,
+            results: HashMap::new(),
+            build_diagnostics: HashMap::new(),
             sender,
-            file_highlights: HashMap::new()
+            file_highlights: HashMap::new(),
+            file_lenses: HashMap::new(),
+            workspace_roots: Vec::new(),
+            supports_progress: false,
+            supports_code_lens_refresh: false,
+            code_action_support: CodeActionSupport::default(),
+            position_encoding: PositionEncoding::default(),
+            published: HashSet::new(),
+            progress_tokens: HashMap::new(),
+            next_request_id: 0
         }
     }
-    pub fn update_from_json(&mut self, p:&str) {
+
+    /// Returns false if the workspace folder is already tracked.
+    pub fn add_workspace(&mut self, root: PathBuf) -> bool {
+        if self.workspace_roots.contains(&root) {
+            return false;
+        }
+        self.workspace_roots.push(root);
+        true
+    }
+
+    /// Stops tracking the workspace folder and drops the results analyzed under it.
+    pub fn remove_workspace(&mut self, root: &Path) {
+        self.workspace_roots.retain(|r| r != root);
+        self.results.retain(|r, _| !r.starts_with(root));
+        self.build_diagnostics.retain(|r, _| !r.starts_with(root));
+        self.rebuild_highlights();
+    }
+
+    /// Whether `root` is in a tracked workspace folder. Analyses of removed folders may
+    /// still report back.
+    pub fn is_tracked(&self, root: &Path) -> bool {
+        self.workspace_roots.iter().any(|r| root.starts_with(r))
+    }
+
+    /// The directory to reanalyze when `file` is saved, if it belongs to a tracked workspace folder.
+    pub fn analysis_root_for(&self, file: &Path) -> Option<PathBuf> {
+        find_analysis_root(file, &self.workspace_roots)
+    }
+
+    /// The directories analyzed so far, with findings or build errors.
+    pub fn analyzed_roots(&self) -> Vec<PathBuf> {
+        let mut roots: Vec<PathBuf> = self.results.keys().chain(self.build_diagnostics.keys()).cloned().collect();
+        roots.sort();
+        roots.dedup();
+        roots
+    }
+
+    /// The directories to analyze in the workspace folder `root`, keyed like the
+    /// analyses `analysis_root_for` starts on save.
+    pub fn analysis_roots_in(&self, root: &Path) -> Vec<PathBuf> {
+        find_analysis_roots(root)
+    }
+    pub fn update_from_json(&mut self, root: PathBuf, p:&str) {
 
         eprintln!("update analysis result: {}", p);
 
         match AnalysisResult::from_file(p) {
             Ok(result) => {
-                self.update_from_analysis_result(result)
+                self.update_from_analysis_result(root, result)
             },
             Err(err) => {
                 eprintln!("update analysis result: {}", err)
@@ -45,9 +116,50 @@ impl GlobalCtxt {
         }
     }
 
-    fn update_from_analysis_result(&mut self, result: AnalysisResult) {
-        self.file_highlights = raw_highlight_to_doc_highlights(&result.critical_sections);
-        self.result = Some(result)
+    /// Replaces the results of `root`, keeping those of the other analyzed directories.
+    pub fn update_from_analysis_result(&mut self, root: PathBuf, result: AnalysisResult) {
+        self.results.insert(root, result);
+        self.rebuild_highlights();
+    }
+
+    /// Drops the result of `root`, e.g. after its analysis failed, so that its findings
+    /// are no longer reported as current.
+    pub fn remove_result(&mut self, root: &Path) {
+        if self.results.remove(root).is_some() {
+            self.rebuild_highlights();
+        }
+    }
+
+    /// Replaces the compiler errors reported by the last analysis build of `root`.
+    pub fn update_build_output(&mut self, root: PathBuf, build: &BuildOutput) {
+        let mut converter = PositionConverter::new(self.position_encoding);
+        let diagnostics = compiler_messages_to_diagnostics(&root, &build.messages, &mut converter);
+        self.build_diagnostics.insert(root, diagnostics);
+    }
+
+    fn rebuild_highlights(&mut self) {
+        let mut file_highlights: IndexedHighlights = HashMap::new();
+        let mut converter = PositionConverter::new(self.position_encoding);
+        let mut file_lenses: HashMap<String, Vec<CodeLens>> = HashMap::new();
+        for result in self.results.values() {
+            merge_indexed(&mut file_highlights, raw_highlight_to_doc_highlights(&result.critical_sections, &mut converter));
+            for area in &result.critical_sections {
+                let mut files: Vec<&str> = area.triggers.iter().map(|t| t.file.as_str()).collect();
+                files.sort();
+                files.dedup();
+                for file in files {
+                    let lenses = code_lens::section_lenses(file, result, area, &mut converter);
+                    file_lenses.entry(file.to_string()).or_default().extend(lenses);
+                }
+            }
+        }
+        self.file_highlights = file_highlights;
+        // Streamed crates often leave the sections as they were.
+        let changed = file_lenses != self.file_lenses;
+        self.file_lenses = file_lenses;
+        if changed && self.supports_code_lens_refresh {
+            self.send_request::<lsp_types::request::CodeLensRefresh>(());
+        }
     }
 
 
@@ -60,10 +172,7 @@ impl GlobalCtxt {
 
                 for area in areas {
                     for h in &area.triggers {
-                        if h.1 <= pos.line && 
-                        h.2 <= pos.character &&
-                        h.3 >= pos.line &&
-                        h.4 >= pos.character {
+                        if contains(h, pos) {
                             eprintln!("found highlight with {:?}", area.triggers);
 
                             return Some(area.areas.to_vec());
@@ -78,57 +187,338 @@ impl GlobalCtxt {
         return None
     }
 
+    /// Answers with a null result where there is no highlight.
     pub fn send_highlight(&mut self, id: RequestId, params: DocumentHighlightParams ) {
-        match self.get_highlights(
-            &params.text_document_position_params.text_document.uri.to_file_path().unwrap().to_str().unwrap().to_string(),
-            &params.text_document_position_params.position,
-        ) {
-            Some(h) => {
-                let res =  lsp_server::Response::new_ok(id, h);
-        
-                match self.sender.send(res.into()) {
-                    Ok(()) => {
-                    },
-                    Err(err) => {
-                        eprintln!("send highlight error: {:?}", err);
+        let position = params.text_document_position_params;
+        let highlights = match position.text_document.uri.to_file_path() {
+            Ok(file) => self.get_highlights(&file.to_string_lossy(), &position.position),
+            Err(()) => None,
+        };
+        let res = lsp_server::Response::new_ok(id, highlights);
+        if let Err(err) = self.sender.send(res.into()) {
+            eprintln!("send highlight error: {:?}", err);
+        }
+    }
+    /// Explains the suspicious call or the critical section whose trigger is at `pos`.
+    fn get_hover(&self, file: &str, pos: &Position) -> Option<Hover> {
+        let mut converter = PositionConverter::new(self.position_encoding);
+        let markdown = |value: String, range: Range| Hover {
+            contents: HoverContents::Markup(MarkupContent { kind: MarkupKind::Markdown, value }),
+            range: Some(range),
+        };
+        for (root, result) in &self.results {
+            for call in &result.calls {
+                let target = match call.callchains.last() {
+                    Some(target) if target.file == file => target,
+                    _ => continue,
+                };
+                let range = converter.range(target);
+                if contains(&range, pos) {
+                    return Some(markdown(hover::call_markdown(root, result, call), range));
+                }
+            }
+            for area in &result.critical_sections {
+                for trigger in area.triggers.iter().filter(|t| t.file == file) {
+                    let range = converter.range(trigger);
+                    if contains(&range, pos) {
+                        return Some(markdown(hover::section_markdown(root, result, area), range));
                     }
                 }
-            },
-            None => {
+            }
+        }
+        None
+    }
 
-            },
+    pub fn send_hover(&mut self, id: RequestId, params: HoverParams) {
+        let position = params.text_document_position_params;
+        let hover = match position.text_document.uri.to_file_path() {
+            Ok(file) => self.get_hover(&file.to_string_lossy(), &position.position),
+            Err(()) => None,
+        };
+        let res = lsp_server::Response::new_ok(id, hover);
+        if let Err(err) = self.sender.send(res.into()) {
+            eprintln!("send hover error: {}", err);
         }
+    }
 
-        
+    pub fn send_prepare_call_hierarchy(&mut self, id: RequestId, params: CallHierarchyPrepareParams) {
+        let position = params.text_document_position_params;
+        let mut converter = PositionConverter::new(self.position_encoding);
+        let items = match position.text_document.uri.to_file_path() {
+            Ok(file) => CallGraph::new(self.results.values()).prepare(&file.to_string_lossy(), &position.position, &mut converter),
+            Err(()) => Vec::new(),
+        };
+        // Clients get a null result where there is no chain.
+        let items = match items.is_empty() {
+            true => None,
+            false => Some(items),
+        };
+        let res = lsp_server::Response::new_ok(id, items);
+        if let Err(err) = self.sender.send(res.into()) {
+            eprintln!("send call hierarchy error: {}", err);
+        }
     }
-    fn get_diagnoistics(&self) -> Option<HashMap<String, Vec<Diagnostic>>> {
-        let analysis = match &self.result {
-            Some(r) => r,
-            None => return None,
+
+    pub fn send_incoming_calls(&mut self, id: RequestId, params: CallHierarchyIncomingCallsParams) {
+        let mut converter = PositionConverter::new(self.position_encoding);
+        let calls = CallGraph::new(self.results.values()).incoming(&params.item, &mut converter);
+        let res = lsp_server::Response::new_ok(id, calls);
+        if let Err(err) = self.sender.send(res.into()) {
+            eprintln!("send incoming calls error: {}", err);
+        }
+    }
+
+    pub fn send_outgoing_calls(&mut self, id: RequestId, params: CallHierarchyOutgoingCallsParams) {
+        let mut converter = PositionConverter::new(self.position_encoding);
+        let calls = CallGraph::new(self.results.values()).outgoing(&params.item, &mut converter);
+        let res = lsp_server::Response::new_ok(id, calls);
+        if let Err(err) = self.sender.send(res.into()) {
+            eprintln!("send outgoing calls error: {}", err);
+        }
+    }
+
+    /// Quick fixes for the diagnostics of suspicious calls among `diagnostics`, which the
+    /// client sends for the range it asks actions for.
+    fn get_code_actions(&self, file: &str, diagnostics: &[Diagnostic]) -> Vec<CodeAction> {
+        let mut converter = PositionConverter::new(self.position_encoding);
+        let mut actions = Vec::new();
+        for d in diagnostics.iter().filter(|d| d.source.as_deref() == Some("rust-deadlock-detector")) {
+            for call in self.results.values().flat_map(|result| &result.calls) {
+                let target = match call.callchains.last() {
+                    Some(target) if target.file == file && code_action::fixable(&call.ty) => target,
+                    _ => continue,
+                };
+                let code = NumberOrString::String(call.ty.name().to_string());
+                if d.code.as_ref() == Some(&code) && converter.range(target) == d.range {
+                    actions.extend(code_action::quick_fixes(call, d, self.code_action_support, &mut converter));
+                }
+            }
+        }
+        actions
+    }
+
+    pub fn send_code_actions(&mut self, id: RequestId, params: CodeActionParams) {
+        let quick_fixes = match &params.context.only {
+            Some(only) => only.iter().any(|kind| CodeActionKind::QUICKFIX.as_str().starts_with(kind.as_str())),
+            None => true,
         };
-        
-        let result = suspicious_calls_to_diagnostics(&analysis.calls);
+        let actions = match params.text_document.uri.to_file_path() {
+            Ok(file) if quick_fixes => self.get_code_actions(&file.to_string_lossy(), &params.context.diagnostics),
+            _ => Vec::new(),
+        };
+        let res = lsp_server::Response::new_ok(id, actions);
+        if let Err(err) = self.sender.send(res.into()) {
+            eprintln!("send code actions error: {}", err);
+        }
+    }
+
+    /// "Reanalyze crate" or "Reanalyze workspace" at the top of `file` if it is in a
+    /// workspace folder, then a summary above every critical section in it.
+    fn get_code_lenses(&self, file: &Path) -> Vec<CodeLens> {
+        let mut lenses = Vec::new();
+        if let Some(root) = self.analysis_root_for(file) {
+            if let Ok(uri) = lsp_types::Url::from_file_path(file) {
+                lenses.push(code_lens::reanalyze_lens(&uri, declares_workspace(&root)));
+            }
+        }
+        if let Some(sections) = self.file_lenses.get(file.to_string_lossy().as_ref()) {
+            lenses.extend(sections.iter().cloned());
+        }
+        lenses
+    }
+
+    pub fn send_code_lens(&mut self, id: RequestId, params: CodeLensParams) {
+        let lenses = match params.text_document.uri.to_file_path() {
+            Ok(file) => self.get_code_lenses(&file),
+            Err(()) => Vec::new(),
+        };
+        let res = lsp_server::Response::new_ok(id, lenses);
+        if let Err(err) = self.sender.send(res.into()) {
+            eprintln!("send code lens error: {}", err);
+        }
+    }
+
+    /// The directory to analyze for the reanalyze command, from the file it was run on.
+    pub fn reanalyze_root(&self, params: &ExecuteCommandParams) -> Result<PathBuf, String> {
+        if params.command != REANALYZE_COMMAND {
+            return Err(format!("unknown command {}", params.command));
+        }
+        let file = params.arguments.first()
+            .and_then(|uri| serde_json::from_value::<lsp_types::Url>(uri.clone()).ok())
+            .and_then(|uri| uri.to_file_path().ok())
+            .ok_or_else(|| format!("{} expects the URI of a file, got {:?}", params.command, params.arguments))?;
+        self.analysis_root_for(&file).ok_or_else(|| format!("{} is not in any workspace folder", file.display()))
+    }
+
+    /// Answers `workspace/executeCommand`, with an error the client shows if the command failed.
+    pub fn send_command_result(&mut self, id: RequestId, result: Result<(), String>) {
+        let res = match result {
+            Ok(()) => lsp_server::Response::new_ok(id, ()),
+            Err(err) => lsp_server::Response::new_err(id, lsp_server::ErrorCode::InvalidParams as i32, err),
+        };
+        if let Err(err) = self.sender.send(res.into()) {
+            eprintln!("send command result error: {}", err);
+        }
+    }
+
+    fn get_diagnoistics(&self) -> Option<HashMap<String, Vec<Diagnostic>>> {
+        if self.results.is_empty() && self.build_diagnostics.is_empty() {
+            return None;
+        }
+
+        let mut result: IndexedDiagnostics = HashMap::new();
+        let mut converter = PositionConverter::new(self.position_encoding);
+        for analysis in self.results.values() {
+            merge_indexed(&mut result, suspicious_calls_to_diagnostics(&analysis.calls, &mut converter));
+            merge_indexed(&mut result, conflicts_to_diagnostics(&analysis.conflicts, &mut converter));
+        }
+        for diagnostics in self.build_diagnostics.values() {
+            merge_indexed(&mut result, diagnostics.clone());
+        }
         return Some(result);
     }
-    pub fn send_message(&mut self) {
+    pub fn send_message(&mut self, typ: MessageType, message: String) {
         self.send_notification::<lsp_types::notification::ShowMessage>(
-            lsp_types::ShowMessageParams { typ: lsp_types::MessageType::INFO, message: "yooooo".to_string() },
+            lsp_types::ShowMessageParams { typ, message },
         );
     }
+    /// Publishes the diagnostics of every file with findings, and empty ones for the
+    /// files published before that have none left.
     pub fn send_diagnoistic(&mut self) {
-        
-        match self.get_diagnoistics() {
-            Some(file_diags) => {
-                for (f, d) in file_diags {
-                    eprintln!("found {} diags for file {}",d.len(), f);
-                    let uri = lsp_types::Url::from_file_path(f).unwrap();
-                    let params = PublishDiagnosticsParams::new(uri, d, None);
-                    self.send_notification::<lsp_types::notification::PublishDiagnostics>(params);
-                }
-            },
+        let file_diags = match self.get_diagnoistics() {
+            Some(file_diags) => file_diags,
             None => {
-                eprintln!("no diagnostic found {:?}", self.result);
+                eprintln!("no diagnostic found {:?}", self.results);
+                HashMap::new()
             },
+        };
+
+        let mut published = HashSet::new();
+        for (f, d) in file_diags {
+            eprintln!("found {} diags for file {}",d.len(), f);
+            let uri = match lsp_types::Url::from_file_path(&f) {
+                Ok(uri) => uri,
+                Err(()) => {
+                    eprintln!("diagnostics for invalid file path {}", f);
+                    continue;
+                },
+            };
+            published.insert(uri.clone());
+            let params = PublishDiagnosticsParams::new(uri, d, None);
+            self.send_notification::<lsp_types::notification::PublishDiagnostics>(params);
+        }
+
+        let stale: Vec<lsp_types::Url> = self.published.difference(&published).cloned().collect();
+        for uri in stale {
+            eprintln!("clear diags for file {}", uri);
+            self.send_notification::<lsp_types::notification::PublishDiagnostics>(PublishDiagnosticsParams::new(uri, Vec::new(), None));
+        }
+        self.published = published;
+    }
+
+    /// Starts a work done progress for the analysis of `root`. A restarted
+    /// analysis keeps reporting to the progress that is already shown.
+    pub fn begin_progress(&mut self, root: &Path) {
+        if !self.supports_progress {
+            return;
+        }
+        if self.progress_tokens.contains_key(root) {
+            self.report_progress(root, "restarted".to_string());
+            return;
+        }
+
+        let token = NumberOrString::String(format!("rust-deadlock-detector/analysis/{}", self.next_request_id));
+        self.send_request::<lsp_types::request::WorkDoneProgressCreate>(
+            WorkDoneProgressCreateParams { token: token.clone() },
+        );
+        self.send_progress(token.clone(), WorkDoneProgress::Begin(WorkDoneProgressBegin {
+            title: "Deadlock analysis".to_string(),
+            cancellable: Some(true),
+            message: Some(root.display().to_string()),
+            percentage: None,
+        }));
+        self.progress_tokens.insert(root.to_path_buf(), token);
+    }
+
+    pub fn report_progress(&mut self, root: &Path, message: String) {
+        if let Some(token) = self.progress_tokens.get(root).cloned() {
+            self.send_progress(token, WorkDoneProgress::Report(WorkDoneProgressReport {
+                cancellable: Some(true),
+                message: Some(message),
+                percentage: None,
+            }));
+        }
+    }
+
+    pub fn end_progress(&mut self, root: &Path, message: String) {
+        if let Some(token) = self.progress_tokens.remove(root) {
+            self.send_progress(token, WorkDoneProgress::End(WorkDoneProgressEnd {
+                message: Some(message),
+            }));
+        }
+    }
+
+    /// The analyzed directory whose progress uses `token`, for cancellation.
+    pub fn workspace_for_progress(&self, token: &ProgressToken) -> Option<PathBuf> {
+        self.progress_tokens.iter().find(|(_, t)| *t == token).map(|(root, _)| root.clone())
+    }
+
+    pub fn findings_summary(&self, root: &Path) -> String {
+        match self.results.get(root) {
+            Some(result) if !result.conflicts.is_empty() => format!(
+                "{} suspicious call(s) in {} critical section(s), {} lock order conflict(s)",
+                result.calls.len(),
+                result.critical_sections.len(),
+                result.conflicts.len()
+            ),
+            Some(result) => format!(
+                "{} suspicious call(s) in {} critical section(s)",
+                result.calls.len(),
+                result.critical_sections.len()
+            ),
+            None => "no result".to_string(),
+        }
+    }
+
+    /// Findings and provenance of every analyzed directory, for the status request.
+    pub fn status(&self) -> String {
+        let mut roots: Vec<&PathBuf> = self.results.keys().collect();
+        roots.sort();
+        let mut lines = Vec::new();
+        for root in roots {
+            lines.push(format!("{}: {}", root.display(), self.findings_summary(root)));
+            if let Some(metadata) = &self.results[root].metadata {
+                lines.push(format!("  {}", metadata.summary()));
+            }
+        }
+        for root in &self.workspace_roots {
+            if !self.results.keys().any(|r| r.starts_with(root)) {
+                lines.push(format!("{}: not analyzed yet", root.display()));
+            }
+        }
+        lines.join("\n")
+    }
+
+    pub fn send_status(&mut self, id: RequestId) {
+        let res = lsp_server::Response::new_ok(id, self.status());
+        if let Err(err) = self.sender.send(res.into()) {
+            eprintln!("send status error: {}", err);
+        }
+    }
+
+    fn send_progress(&mut self, token: ProgressToken, progress: WorkDoneProgress) {
+        self.send_notification::<lsp_types::notification::Progress>(ProgressParams {
+            token,
+            value: ProgressParamsValue::WorkDone(progress),
+        });
+    }
+
+    pub fn send_request<R: lsp_types::request::Request>(&mut self, params: R::Params) {
+        let id = RequestId::from(self.next_request_id);
+        self.next_request_id += 1;
+        let req = lsp_server::Request::new(id, R::METHOD.to_string(), params);
+        if let Err(err) = self.sender.send(req.into()) {
+            eprintln!("send request error: {}", err);
         }
     }
 
@@ -148,95 +538,249 @@ impl GlobalCtxt {
 
 }
 
-fn raw_highlight_to_doc_highlights(raw: &Vec<HighlightArea>) -> IndexedHighlights {
+fn merge_indexed<T>(into: &mut HashMap<String, Vec<T>>, from: HashMap<String, Vec<T>>) {
+    for (file, items) in from {
+        into.entry(file).or_default().extend(items);
+    }
+}
+
+/// Sections of every `SectionKind` are highlighted alike, from the lock or the
+/// `interrupt::free`/`critical_section::with` call that starts them.
+fn raw_highlight_to_doc_highlights(raw: &Vec<HighlightArea>, converter: &mut PositionConverter) -> IndexedHighlights {
     let mut ih: IndexedHighlights = HashMap::new();
     for r in raw {
         let mut highlights: Vec<DocumentHighlight> = Vec::new();
-        let filename = &r.ranges.first().unwrap().0;
+        let filename = match r.ranges.first() {
+            Some(first) => &first.file,
+            None => continue,
+        };
         for h in &r.ranges {
             let h: DocumentHighlight = DocumentHighlight {
-                range: Range {
-                    start: Position {
-                        line: h.1-1,
-                        character: h.2-1,
-                    },
-                    end: Position {
-                        line: h.3-1,
-                        character: h.4-1,
-                    },
-                },
+                range: converter.range(h),
                 kind: Some(DocumentHighlightKind::TEXT)
             };
             highlights.push(h);
         }
+        // Points where the task may suspend with the lock held stand out from the section.
+        for a in &r.await_points {
+            highlights.push(DocumentHighlight { range: converter.range(a), kind: Some(DocumentHighlightKind::WRITE) });
+        }
         
         if !ih.contains_key(filename) {
             ih.insert(filename.to_string(), Vec::new());
         }
 
-        let zero_based_triggers: Vec<RangeInFile> = r.triggers.iter().map(|t| (t.0.clone(), t.1-1, t.2-1, t.3-1, t.4-1)).collect();
-        ih.get_mut(filename).unwrap().push(DocHighlightsWithTrigger { areas: highlights, triggers: zero_based_triggers } );
+        let triggers: Vec<Range> = r.triggers.iter().map(|t| converter.range(t)).collect();
+        ih.get_mut(filename).unwrap().push(DocHighlightsWithTrigger { areas: highlights, triggers } );
         
     }
     return ih;
 }
 
 
-fn suspicious_calls_to_diagnostics(calls: &Vec<SuspiciousCall>) ->IndexedDiagnostics {
+fn suspicious_calls_to_diagnostics(calls: &Vec<SuspiciousCall>, converter: &mut PositionConverter) ->IndexedDiagnostics {
     let mut result: IndexedDiagnostics = HashMap::new();
     for call in calls {
-        if call.callchains.len() == 0 {
-            eprintln!("unexpected callchain found {:?}", call);
-        }
-        let target = call.callchains.last().unwrap();
+        let target = match call.callchains.last() {
+            Some(target) => target,
+            None => {
+                eprintln!("unexpected callchain found {:?}", call);
+                continue;
+            },
+        };
         let relateds = &call.callchains[..call.callchains.len()-1];
         let mut d = Diagnostic {
-            range: lsp_types::Range { 
-                start: Position { line: target.1-1, character: target.2-1}, 
-                end: Position { line: target.3-1, character: target.4-1 }
-            },
+            range: converter.range(target),
             severity: Some(DiagnosticSeverity::INFORMATION),
             code: None,
             code_description: None,
             source: Some("rust-deadlock-detector".to_string()),
-            message: format!("{:?} in critical section", call.ty),
+            message: call_message(call),
             related_information: None,
             tags: None,
             data: None,
         };
+        classify(&mut d, &call.ty);
 
       
-        let drelateds:Vec<DiagnosticRelatedInformation> = relateds.iter().map(|r|{
-            let uri = lsp_types::Url::from_file_path(&r.0).unwrap();
-
-            DiagnosticRelatedInformation {
-                location: Location {
-                    uri,
-                    range: lsp_types::Range { 
-                        start: Position { line: r.1-1, character: r.2-1}, 
-                        end: Position { line: r.3-1, character: r.4-1 }
-                    }
-                },
-                message: "may contains blocking call in critical section".to_string(),
-            }
-        })
-        .collect();
+        let mut drelateds:Vec<DiagnosticRelatedInformation> = relateds.iter()
+            .filter_map(|r| related(r, "may contains blocking call in critical section".to_string(), converter))
+            .collect();
 
+        drelateds.extend(lock_related_information(call, converter));
         if drelateds.len() > 0 {
             d.related_information = Some(drelateds);
         }
 
 
-        if !result.contains_key(&target.0) {
-            result.insert(target.0.clone(), Vec::new());
+        if !result.contains_key(&target.file) {
+            result.insert(target.file.clone(), Vec::new());
         }
-        result.get_mut(&target.0).unwrap().push(d);
+        result.get_mut(&target.file).unwrap().push(d);
         
 
     }
 
     result
 }
+/// Names the locks involved, if lockbud reported them, and why the call is suspicious.
+fn call_message(call: &SuspiciousCall) -> String {
+    let name = call.ty.name();
+    let locks: Vec<String> = call.locks.iter().map(LockInfo::describe).collect();
+    let context = call.ty.context();
+    let subject = match locks.as_slice() {
+        [] => format!("{} in {}", name, context),
+        [lock] => format!("{} in {} of {}", name, context, lock),
+        [init @ .., last] => format!("{} in {} of {} and {}", name, context, init.join(", "), last),
+    };
+    format!("{}: {}", subject, call.description())
+}
+
+/// Kinds that deadlock whenever the path runs are warnings, calls that may block are information.
+fn kind_severity(kind: &Suspicious) -> DiagnosticSeverity {
+    match kind {
+        Suspicious::DoubleLock
+        | Suspicious::ConflictLock
+        | Suspicious::RwLockReadThenWrite
+        | Suspicious::RwLockUpgrade
+        | Suspicious::OnceReentrant
+        | Suspicious::GuardAcrossAwait
+        | Suspicious::AsyncConflictLock
+        | Suspicious::IsrConflictLock => DiagnosticSeverity::WARNING,
+        _ => DiagnosticSeverity::INFORMATION,
+    }
+}
+
+/// Documentation of the primitive a kind is about.
+fn kind_doc_url(kind: &Suspicious) -> Option<&'static str> {
+    Some(match kind {
+        Suspicious::ChSend => "https://doc.rust-lang.org/std/sync/mpsc/struct.SyncSender.html#method.send",
+        Suspicious::ChRecv => "https://doc.rust-lang.org/std/sync/mpsc/struct.Receiver.html#method.recv",
+        Suspicious::CondVarWait => "https://doc.rust-lang.org/std/sync/struct.Condvar.html#method.wait",
+        Suspicious::DoubleLock | Suspicious::ConflictLock => "https://doc.rust-lang.org/std/sync/struct.Mutex.html#method.lock",
+        Suspicious::RwLockRecursiveRead => "https://doc.rust-lang.org/std/sync/struct.RwLock.html#method.read",
+        Suspicious::RwLockReadThenWrite => "https://doc.rust-lang.org/std/sync/struct.RwLock.html#method.write",
+        Suspicious::RwLockUpgrade => "https://docs.rs/parking_lot/latest/parking_lot/type.RwLock.html",
+        Suspicious::OnceReentrant => "https://doc.rust-lang.org/std/sync/struct.Once.html#method.call_once",
+        Suspicious::BarrierWait => "https://doc.rust-lang.org/std/sync/struct.Barrier.html#method.wait",
+        Suspicious::ThreadJoin => "https://doc.rust-lang.org/std/thread/struct.JoinHandle.html#method.join",
+        Suspicious::GuardAcrossAwait => "https://docs.rs/tokio/latest/tokio/sync/struct.Mutex.html#which-kind-of-mutex-should-you-use",
+        Suspicious::AsyncConflictLock => "https://docs.rs/tokio/latest/tokio/sync/struct.Mutex.html",
+        Suspicious::BlockingInAsync => "https://docs.rs/tokio/latest/tokio/task/fn.spawn_blocking.html",
+        Suspicious::IsrConflictLock => "https://docs.rs/critical-section/latest/critical_section/",
+        Suspicious::Unknown(_) => return None,
+    })
+}
+
+/// Severity, code and documentation link of a diagnostic about `kind`.
+fn classify(d: &mut Diagnostic, kind: &Suspicious) {
+    d.severity = Some(kind_severity(kind));
+    d.code = Some(NumberOrString::String(kind.name().to_string()));
+    d.code_description = kind_doc_url(kind)
+        .and_then(|url| lsp_types::Url::parse(url).ok())
+        .map(|href| CodeDescription { href });
+}
+
+/// Points to where each lock of `call` was acquired.
+fn lock_related_information(call: &SuspiciousCall, converter: &mut PositionConverter) -> Vec<DiagnosticRelatedInformation> {
+    call.locks
+        .iter()
+        .filter_map(|lock| related(&lock.acquired_at, format!("{} acquired here", lock.describe()), converter))
+        .collect()
+}
+
+/// A diagnostic at the last frame of each side of every conflict, linked to the
+/// opposite order so that the whole inversion can be followed from either file.
+fn conflicts_to_diagnostics(conflicts: &[LockConflict], converter: &mut PositionConverter) -> IndexedDiagnostics {
+    let mut result: IndexedDiagnostics = HashMap::new();
+    for conflict in conflicts {
+        for (i, side) in conflict.sides.iter().enumerate() {
+            let other = &conflict.sides[1 - i];
+            let target = match side.callchains.last() {
+                Some(target) => target,
+                None => continue,
+            };
+
+            let mut relateds: Vec<DiagnosticRelatedInformation> = Vec::new();
+            relateds.extend(related(&side.first.acquired_at, format!("{} acquired here", side.first.describe()), converter));
+            for r in &side.callchains[..side.callchains.len() - 1] {
+                relateds.extend(related(r, "called from here".to_string(), converter));
+            }
+            if let Some(other_target) = other.callchains.last() {
+                relateds.extend(related(
+                    other_target,
+                    format!("opposite order: {} acquired while holding {}", other.second.describe(), other.first.describe()),
+                    converter,
+                ));
+            }
+            relateds.extend(related(&other.first.acquired_at, format!("{} acquired here", other.first.describe()), converter));
+
+            // Tasks waiting on async locks do not block threads, but still never wake up.
+            let kind = if [&side.first, &side.second].iter().all(|l| l.is_async()) {
+                Suspicious::AsyncConflictLock
+            } else {
+                Suspicious::ConflictLock
+            };
+            let mut d = Diagnostic {
+                range: converter.range(target),
+                severity: Some(DiagnosticSeverity::INFORMATION),
+                code: None,
+                code_description: None,
+                source: Some("rust-deadlock-detector".to_string()),
+                message: format!(
+                    "{}: {} acquired while holding {}, in the opposite order elsewhere",
+                    kind.name(),
+                    side.second.describe(),
+                    side.first.describe()
+                ),
+                related_information: Some(relateds),
+                tags: None,
+                data: None,
+            };
+            classify(&mut d, &kind);
+            result.entry(target.file.clone()).or_default().push(d);
+        }
+    }
+    result
+}
+
+fn related(r: &RangeInFile, message: String, converter: &mut PositionConverter) -> Option<DiagnosticRelatedInformation> {
+    let uri = lsp_types::Url::from_file_path(&r.file).ok()?;
+    Some(DiagnosticRelatedInformation {
+        location: Location { uri, range: converter.range(r) },
+        message,
+    })
+}
+
+/// Compiler errors from the analysis build, reported at their primary span.
+fn compiler_messages_to_diagnostics(root: &Path, messages: &[CompilerMessage], converter: &mut PositionConverter) -> IndexedDiagnostics {
+    let mut result: IndexedDiagnostics = HashMap::new();
+    for m in messages.iter().filter(|m| m.is_error()) {
+        let span = match m.primary_span() {
+            Some(span) => span,
+            None => continue,
+        };
+        // rustc counts columns in chars as well.
+        let file = root.join(&span.file_name).to_string_lossy().to_string();
+        let d = Diagnostic {
+            range: lsp_types::Range {
+                start: converter.position(&file, LineCol { line: span.line_start, col: span.column_start }),
+                end: converter.position(&file, LineCol { line: span.line_end, col: span.column_end }),
+            },
+            severity: Some(DiagnosticSeverity::ERROR),
+            code: m.code.as_ref().map(|c| NumberOrString::String(c.code.clone())),
+            code_description: None,
+            source: Some("rust-deadlock-detector".to_string()),
+            message: format!("deadlock analysis build failed: {}", m.message),
+            related_information: None,
+            tags: None,
+            data: None,
+        };
+
+        result.entry(file).or_default().push(d);
+    }
+    result
+}
 
 #[cfg(test)]
 mod tests {
@@ -244,7 +788,7 @@ mod tests {
 
     use crossbeam_channel::unbounded;
 
-    use crate::lsp::lockbud_ty::Suspicious;
+    use crate::lsp::lockbud_ty::{LockOrder, Metadata, SectionKind, SCHEMA_VERSION};
 
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
@@ -254,10 +798,31 @@ mod tests {
     fn test_global_ctx_init() {
         let (s1, _) = unbounded();
         let ctx = GlobalCtxt::new(s1);
-        assert!(ctx.result.is_none());
+        assert!(ctx.results.is_empty());
         assert!(ctx.file_highlights.is_empty());
     }
 
+    #[test]
+    fn test_global_ctx_workspaces() {
+        let (s1, _) = unbounded();
+        let mut ctx = GlobalCtxt::new(s1);
+        assert!(ctx.add_workspace(PathBuf::from("/ws1")));
+        assert!(ctx.add_workspace(PathBuf::from("/ws2")));
+        assert!(!ctx.add_workspace(PathBuf::from("/ws1")));
+
+        assert_eq!(ctx.analysis_root_for(Path::new("/ws2/src/main.rs")), Some(PathBuf::from("/ws2")));
+
+        assert!(ctx.is_tracked(Path::new("/ws2/crates/foo")));
+        ctx.update_from_analysis_result(PathBuf::from("/ws2/crates/foo"), AnalysisResult::new(Vec::new(), Vec::new()));
+        ctx.build_diagnostics.insert(PathBuf::from("/ws1/bar"), IndexedDiagnostics::default());
+        assert_eq!(ctx.analyzed_roots(), vec![PathBuf::from("/ws1/bar"), PathBuf::from("/ws2/crates/foo")]);
+
+        ctx.remove_workspace(Path::new("/ws2"));
+        assert_eq!(ctx.workspace_roots, vec![PathBuf::from("/ws1")]);
+        assert!(!ctx.is_tracked(Path::new("/ws2/crates/foo")));
+        assert!(ctx.analysis_root_for(Path::new("/ws2/src/main.rs")).is_none());
+    }
+
 
     #[test]
     fn test_global_ctx_update_from_file() -> Result<(),Box<dyn Error>>  {
@@ -267,50 +832,53 @@ mod tests {
     
         let mut calls: Vec<SuspiciousCall> = Vec::new();
         calls.push(SuspiciousCall { callchains: vec![
-            ("/some/file1.rs".to_string(), 4, 5, 6, 7)
-        ], ty: Suspicious::DoubleLock });
+            RangeInFile::new("/some/file1.rs", 4, 5, 6, 7)
+        ], ty: Suspicious::DoubleLock, locks: Vec::new() });
         calls.push(SuspiciousCall { callchains: vec![
-            ("/some/file1.rs".to_string(), 6, 7, 8, 9)
-        ], ty: Suspicious::ConflictLock });
+            RangeInFile::new("/some/file1.rs", 6, 7, 8, 9)
+        ], ty: Suspicious::ConflictLock, locks: Vec::new() });
 
         calls.push(SuspiciousCall { callchains: vec![
-            ("/some/file2.rs".to_string(), 6, 7, 8, 9)
-        ], ty: Suspicious::ChRecv });
+            RangeInFile::new("/some/file2.rs", 6, 7, 8, 9)
+        ], ty: Suspicious::ChRecv, locks: Vec::new() });
 
         calls.push(SuspiciousCall { callchains: vec![
-            ("/some/file3.rs".to_string(), 6, 7, 8, 9)
-        ], ty: Suspicious::ChSend });
+            RangeInFile::new("/some/file3.rs", 6, 7, 8, 9)
+        ], ty: Suspicious::ChSend, locks: Vec::new() });
 
         calls.push(SuspiciousCall { callchains: vec![
-            ("/some/file4.rs".to_string(), 6, 7, 8, 9)
-        ], ty: Suspicious::CondVarWait });
+            RangeInFile::new("/some/file4.rs", 6, 7, 8, 9)
+        ], ty: Suspicious::CondVarWait, locks: Vec::new() });
 
         let result = AnalysisResult {
+            schema_version: SCHEMA_VERSION,
+            metadata: None,
+            conflicts: Vec::new(),
             calls,
             critical_sections: vec![
                 HighlightArea { triggers: vec![
-                    ("file1.rs".to_string(), 1, 2, 3, 4)
+                    RangeInFile::new("/some/file1.rs", 1, 2, 3, 4)
                 ], ranges: vec![
-                    ("file1.rs".to_string(), 5, 6, 7, 8)
-                ] },
+                    RangeInFile::new("/some/file1.rs", 5, 6, 7, 8)
+                ], await_points: Vec::new(), kind: SectionKind::Lock },
     
                 HighlightArea { triggers: vec![
-                    ("file2.rs".to_string(), 1, 2, 3, 4)
+                    RangeInFile::new("/some/file2.rs", 1, 2, 3, 4)
                 ], ranges: vec![
-                    ("file2.rs".to_string(), 5, 6, 7, 8)
-                ] }
+                    RangeInFile::new("/some/file2.rs", 5, 6, 7, 8)
+                ], await_points: Vec::new(), kind: SectionKind::Lock }
             ],
         };
 
         result.to_file(tmp_result_file).unwrap();
         let (s1, _) = unbounded();
         let mut ctx = GlobalCtxt::new(s1);
-        assert!(ctx.result.is_none());
+        assert!(ctx.results.is_empty());
         assert!(ctx.file_highlights.is_empty());
 
-        ctx.update_from_json(tmp_result_file);
+        ctx.update_from_json(PathBuf::from("/ws"), tmp_result_file);
 
-        assert!(ctx.result.is_some());
+        assert!(ctx.results.contains_key(Path::new("/ws")));
         assert!(!ctx.file_highlights.is_empty());
 
 
@@ -322,33 +890,116 @@ mod tests {
     fn test_global_ctx_get_heightlights() -> Result<(),Box<dyn Error>>  {
 
         let result = AnalysisResult {
+            schema_version: SCHEMA_VERSION,
+            metadata: None,
+            conflicts: Vec::new(),
             calls:Vec::new(),
             critical_sections: vec![
                 HighlightArea { triggers: vec![
-                    ("file1.rs".to_string(), 1, 2, 3, 4)
+                    RangeInFile::new("file1.rs", 1, 2, 3, 4)
                 ], ranges: vec![
-                    ("file1.rs".to_string(), 5, 6, 7, 8)
-                ] },
+                    RangeInFile::new("file1.rs", 5, 6, 7, 8)
+                ], await_points: Vec::new(), kind: SectionKind::Lock },
     
                 HighlightArea { triggers: vec![
-                    ("file2.rs".to_string(), 1, 2, 3, 4)
+                    RangeInFile::new("file2.rs", 1, 2, 3, 4)
                 ], ranges: vec![
-                    ("file2.rs".to_string(), 5, 6, 7, 8)
-                ] }
+                    RangeInFile::new("file2.rs", 5, 6, 7, 8)
+                ], await_points: Vec::new(), kind: SectionKind::Lock }
             ],
         };
 
         let (s1, _) = unbounded();
         let mut ctx = GlobalCtxt::new(s1);
-        ctx.update_from_analysis_result(result);
+        ctx.update_from_analysis_result(PathBuf::from("/ws"), result);
 
         let areas = ctx.get_highlights("file1.rs", &Position { line: 1, character: 3 });
         
         assert!(areas.is_some());
         assert_eq!(areas.unwrap().len(), 1);
+
+        // The trigger spans lines 0 to 2, its columns only bound the first and last line.
+        assert!(ctx.get_highlights("file1.rs", &Position { line: 1, character: 10 }).is_some());
+        assert!(ctx.get_highlights("file1.rs", &Position { line: 2, character: 0 }).is_some());
+        assert!(ctx.get_highlights("file1.rs", &Position { line: 0, character: 0 }).is_none());
+        assert!(ctx.get_highlights("file1.rs", &Position { line: 2, character: 4 }).is_none());
         Ok(())
     }
 
+    #[test]
+    fn test_global_ctx_send_highlight_always_answers() {
+        let (s1, r1) = unbounded();
+        let mut ctx = GlobalCtxt::new(s1);
+        let params = |uri: &str| DocumentHighlightParams {
+            text_document_position_params: lsp_types::TextDocumentPositionParams {
+                text_document: lsp_types::TextDocumentIdentifier { uri: lsp_types::Url::parse(uri).unwrap() },
+                position: Position { line: 0, character: 0 },
+            },
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        };
+        for uri in ["file:///ws/a.rs", "untitled:Untitled-1"] {
+            ctx.send_highlight(RequestId::from(1), params(uri));
+            match r1.try_recv().unwrap() {
+                Message::Response(res) => assert_eq!(res.result, Some(serde_json::Value::Null)),
+                msg => panic!("unexpected message {:?}", msg),
+            }
+        }
+    }
+
+    #[test]
+    fn test_global_ctx_results_per_workspace() {
+        let area = |file: &str| HighlightArea {
+            triggers: vec![RangeInFile::new(file, 1, 2, 3, 4)],
+            ranges: vec![RangeInFile::new(file, 5, 6, 7, 8)],
+            await_points: Vec::new(),
+            kind: SectionKind::Lock,
+        };
+        let call = |file: &str| SuspiciousCall {
+            callchains: vec![RangeInFile::new(file, 4, 5, 6, 7)],
+            ty: Suspicious::DoubleLock,
+            locks: Vec::new(),
+        };
+
+        let (s1, _) = unbounded();
+        let mut ctx = GlobalCtxt::new(s1);
+        ctx.add_workspace(PathBuf::from("/ws1"));
+        ctx.add_workspace(PathBuf::from("/ws2"));
+        ctx.update_from_analysis_result(PathBuf::from("/ws1"), AnalysisResult {
+            schema_version: SCHEMA_VERSION,
+            metadata: None,
+            conflicts: Vec::new(),
+            calls: vec![call("/ws1/a.rs")],
+            critical_sections: vec![area("/ws1/a.rs")],
+        });
+        ctx.update_from_analysis_result(PathBuf::from("/ws2"), AnalysisResult {
+            schema_version: SCHEMA_VERSION,
+            metadata: None,
+            conflicts: Vec::new(),
+            calls: vec![call("/ws2/b.rs")],
+            critical_sections: vec![area("/ws2/b.rs")],
+        });
+
+        assert_eq!(ctx.file_highlights.len(), 2);
+        assert_eq!(ctx.get_diagnoistics().unwrap().len(), 2);
+
+        // Reanalyzing one folder only replaces its own slice.
+        ctx.update_from_analysis_result(PathBuf::from("/ws1"), AnalysisResult {
+            schema_version: SCHEMA_VERSION,
+            metadata: None,
+            conflicts: Vec::new(),
+            calls: Vec::new(),
+            critical_sections: Vec::new(),
+        });
+        assert!(!ctx.file_highlights.contains_key("/ws1/a.rs"));
+        assert!(ctx.file_highlights.contains_key("/ws2/b.rs"));
+        assert_eq!(ctx.get_diagnoistics().unwrap().len(), 1);
+
+        ctx.remove_workspace(Path::new("/ws2"));
+        assert!(ctx.file_highlights.is_empty());
+        assert!(ctx.get_diagnoistics().unwrap().is_empty());
+    }
+
 
     /// 
     /// The test make sures the files and their highlight areas are properly
@@ -360,18 +1011,18 @@ mod tests {
         
         let raw_highlights = vec![
             HighlightArea { triggers: vec![
-                ("file1.rs".to_string(), 1, 2, 3, 4)
+                RangeInFile::new("file1.rs", 1, 2, 3, 4)
             ], ranges: vec![
-                ("file1.rs".to_string(), 5, 6, 7, 8)
-            ] },
+                RangeInFile::new("file1.rs", 5, 6, 7, 8)
+            ], await_points: Vec::new(), kind: SectionKind::Lock },
 
             HighlightArea { triggers: vec![
-                ("file2.rs".to_string(), 1, 2, 3, 4)
+                RangeInFile::new("file2.rs", 1, 2, 3, 4)
             ], ranges: vec![
-                ("file2.rs".to_string(), 5, 6, 7, 8)
-            ] }
+                RangeInFile::new("file2.rs", 5, 6, 7, 8)
+            ], await_points: Vec::new(), kind: SectionKind::Lock }
         ];
-        let res = raw_highlight_to_doc_highlights(&raw_highlights);
+        let res = raw_highlight_to_doc_highlights(&raw_highlights, &mut PositionConverter::new(PositionEncoding::Utf16));
 
         assert_eq!(2, res.len());
         assert_eq!(1, res.get("file1.rs").unwrap().len());
@@ -390,26 +1041,25 @@ mod tests {
         
         let raw_highlights = vec![
             HighlightArea { triggers: vec![
-                ("file1.rs".to_string(), 1, 2, 3, 4)
+                RangeInFile::new("file1.rs", 1, 2, 3, 4)
             ], ranges: vec![
-                ("file1.rs".to_string(), 5, 6, 7, 8)
-            ] },
+                RangeInFile::new("file1.rs", 5, 6, 7, 8)
+            ], await_points: Vec::new(), kind: SectionKind::Lock },
 
             HighlightArea { triggers: vec![
-                ("file2.rs".to_string(), 1, 2, 3, 4)
+                RangeInFile::new("file2.rs", 1, 2, 3, 4)
             ], ranges: vec![
-                ("file2.rs".to_string(), 5, 6, 7, 8)
-            ] }
+                RangeInFile::new("file2.rs", 5, 6, 7, 8)
+            ], await_points: Vec::new(), kind: SectionKind::Lock }
         ];
-        let res = raw_highlight_to_doc_highlights(&raw_highlights);
+        let res = raw_highlight_to_doc_highlights(&raw_highlights, &mut PositionConverter::new(PositionEncoding::Utf16));
 
         let highlight_with_trigger = res.get("file1.rs").unwrap();
         let trigger = highlight_with_trigger.first().unwrap().triggers.first().unwrap();
-        assert_eq!(trigger.0, "file1.rs");
-        assert_eq!(trigger.1, 0);
-        assert_eq!(trigger.2, 1);
-        assert_eq!(trigger.3, 2);
-        assert_eq!(trigger.4, 3);
+        assert_eq!(trigger.start.line, 0);
+        assert_eq!(trigger.start.character, 1);
+        assert_eq!(trigger.end.line, 2);
+        assert_eq!(trigger.end.character, 3);
         
         let area = highlight_with_trigger.first().unwrap().areas.first().unwrap();
 
@@ -428,12 +1078,12 @@ mod tests {
     fn test_suspicious_calls_to_diagnostics_one_callchain() {
         let mut calls: Vec<SuspiciousCall> = Vec::new();
         calls.push(SuspiciousCall { callchains: vec![
-            ("/some/file1.rs".to_string(), 4, 5, 6, 7)
-        ], ty: Suspicious::DoubleLock });
+            RangeInFile::new("/some/file1.rs", 4, 5, 6, 7)
+        ], ty: Suspicious::DoubleLock, locks: Vec::new() });
         calls.push(SuspiciousCall { callchains: vec![
-            ("/some/file1.rs".to_string(), 6, 7, 8, 9)
-        ], ty: Suspicious::ConflictLock });
-        let result = suspicious_calls_to_diagnostics(&calls);
+            RangeInFile::new("/some/file1.rs", 6, 7, 8, 9)
+        ], ty: Suspicious::ConflictLock, locks: Vec::new() });
+        let result = suspicious_calls_to_diagnostics(&calls, &mut PositionConverter::new(PositionEncoding::Utf16));
         
         assert_eq!(result.len(), 1);
         assert_eq!(result.get("/some/file1.rs").unwrap().len(), 2);
@@ -453,14 +1103,14 @@ mod tests {
     fn test_suspicious_calls_to_diagnostics() {
         let mut calls: Vec<SuspiciousCall> = Vec::new();
         calls.push(SuspiciousCall { callchains: vec![
-            ("/some/file1.rs".to_string(), 1, 2, 3, 4),
-            ("/some/file1.rs".to_string(), 4, 5, 6, 7)
-        ], ty: Suspicious::DoubleLock });
+            RangeInFile::new("/some/file1.rs", 1, 2, 3, 4),
+            RangeInFile::new("/some/file1.rs", 4, 5, 6, 7)
+        ], ty: Suspicious::DoubleLock, locks: Vec::new() });
         calls.push(SuspiciousCall { callchains: vec![
-            ("/some/file1.rs".to_string(), 2, 3, 4, 5),
-            ("/some/file1.rs".to_string(), 6, 7, 8, 9)
-        ], ty: Suspicious::ConflictLock });
-        let result = suspicious_calls_to_diagnostics(&calls);
+            RangeInFile::new("/some/file1.rs", 2, 3, 4, 5),
+            RangeInFile::new("/some/file1.rs", 6, 7, 8, 9)
+        ], ty: Suspicious::ConflictLock, locks: Vec::new() });
+        let result = suspicious_calls_to_diagnostics(&calls, &mut PositionConverter::new(PositionEncoding::Utf16));
         
         assert_eq!(result.len(), 1);
         assert_eq!(result.get("/some/file1.rs").unwrap().len(), 2);
@@ -480,4 +1130,443 @@ mod tests {
         assert_eq!(first_loc.range.end.character, 3);
     }
     
-}
\ No newline at end of file
+
+    ///
+    /// Test the locks lockbud reported are named in the diagnostic and linked to where they were acquired.
+    ///
+    #[test]
+    fn test_suspicious_calls_to_diagnostics_with_locks() {
+        let lock = |expr: &str, line: u32| LockInfo {
+            expr: expr.to_string(),
+            ty: "std::sync::Mutex<i32>".to_string(),
+            acquired_at: RangeInFile::new("/some/file1.rs", line, 9, line, 20),
+        };
+        let calls = vec![
+            SuspiciousCall {
+                callchains: vec![RangeInFile::new("/some/file1.rs", 4, 5, 6, 7)],
+                ty: Suspicious::DoubleLock,
+                locks: vec![lock("self.a", 2)],
+            },
+            SuspiciousCall {
+                callchains: vec![RangeInFile::new("/some/file1.rs", 8, 5, 8, 7)],
+                ty: Suspicious::ConflictLock,
+                locks: vec![lock("self.a", 2), lock("self.b", 3), lock("self.c", 4)],
+            },
+        ];
+        let result = suspicious_calls_to_diagnostics(&calls, &mut PositionConverter::new(PositionEncoding::Utf16));
+        let diags = result.get("/some/file1.rs").unwrap();
+
+        assert_eq!(
+            diags[0].message,
+            "DoubleLock in critical section of `self.a` (std::sync::Mutex<i32>): the lock is acquired again while it is held, which deadlocks or panics"
+        );
+        let related = diags[0].related_information.as_ref().unwrap();
+        assert_eq!(related.len(), 1);
+        assert_eq!(related[0].message, "`self.a` (std::sync::Mutex<i32>) acquired here");
+        assert_eq!(related[0].location.range.start, Position { line: 1, character: 8 });
+
+        assert!(diags[1].message.starts_with(
+            "ConflictLock in critical section of `self.a` (std::sync::Mutex<i32>), `self.b` (std::sync::Mutex<i32>) and `self.c` (std::sync::Mutex<i32>): "
+        ));
+        assert_eq!(diags[1].related_information.as_ref().unwrap().len(), 3);
+    }
+
+    ///
+    /// Test each kind gets its own severity, code and documentation link, also kinds from a newer lockbud.
+    ///
+    #[test]
+    fn test_suspicious_calls_to_diagnostics_classifies_kinds() {
+        let call = |ty: Suspicious, lock_ty: &str| SuspiciousCall {
+            callchains: vec![RangeInFile::new("/some/file1.rs", 4, 5, 6, 7)],
+            ty,
+            locks: vec![LockInfo {
+                expr: "LOCK".to_string(),
+                ty: lock_ty.to_string(),
+                acquired_at: RangeInFile::new("/some/file1.rs", 2, 5, 2, 9),
+            }],
+        };
+        let calls = vec![
+            call(Suspicious::ThreadJoin, "std::sync::Mutex<()>"),
+            call(Suspicious::DoubleLock, "spin::Mutex<()>"),
+            call(Suspicious::Unknown("SemaphoreAcquire".to_string()), "tokio::sync::Semaphore"),
+        ];
+        let result = suspicious_calls_to_diagnostics(&calls, &mut PositionConverter::new(PositionEncoding::Utf16));
+        let diags = result.get("/some/file1.rs").unwrap();
+
+        assert_eq!(diags[0].severity, Some(DiagnosticSeverity::INFORMATION));
+        assert_eq!(diags[0].code, Some(NumberOrString::String("ThreadJoin".to_string())));
+        assert_eq!(
+            diags[0].code_description.as_ref().unwrap().href.as_str(),
+            "https://doc.rust-lang.org/std/thread/struct.JoinHandle.html#method.join"
+        );
+
+        assert_eq!(diags[1].severity, Some(DiagnosticSeverity::WARNING));
+        assert!(diags[1].message.ends_with("the thread spins forever"));
+
+        assert!(diags[2].message.starts_with("SemaphoreAcquire in critical section"));
+        assert!(diags[2].code_description.is_none());
+    }
+
+    ///
+    /// Test a lock-order inversion is reported at both sites, each linked to the opposite order.
+    ///
+    #[test]
+    fn test_conflicts_to_diagnostics() {
+        let lock = |expr: &str, file: &str, line: u32| LockInfo {
+            expr: expr.to_string(),
+            ty: "std::sync::Mutex<i32>".to_string(),
+            acquired_at: RangeInFile::new(file, line, 9, line, 20),
+        };
+        let conflict = LockConflict {
+            sides: [
+                LockOrder {
+                    first: lock("self.a", "/ws/a.rs", 2),
+                    second: lock("self.b", "/ws/a.rs", 4),
+                    callchains: vec![RangeInFile::new("/ws/a.rs", 3, 5, 3, 12), RangeInFile::new("/ws/a.rs", 4, 9, 4, 20)],
+                },
+                LockOrder {
+                    first: lock("self.b", "/ws/b.rs", 7),
+                    second: lock("self.a", "/ws/b.rs", 8),
+                    callchains: vec![RangeInFile::new("/ws/b.rs", 8, 9, 8, 20)],
+                },
+            ],
+        };
+        let result = conflicts_to_diagnostics(&[conflict], &mut PositionConverter::new(PositionEncoding::Utf16));
+        assert_eq!(result.len(), 2);
+
+        let a = &result["/ws/a.rs"][0];
+        assert_eq!(a.range.start, Position { line: 3, character: 8 });
+        assert_eq!(
+            a.message,
+            "ConflictLock: `self.b` (std::sync::Mutex<i32>) acquired while holding `self.a` (std::sync::Mutex<i32>), in the opposite order elsewhere"
+        );
+        let related: Vec<(&str, &str)> = a.related_information.as_ref().unwrap().iter()
+            .map(|r| (r.location.uri.path(), r.message.as_str()))
+            .collect();
+        assert_eq!(related, vec![
+            ("/ws/a.rs", "`self.a` (std::sync::Mutex<i32>) acquired here"),
+            ("/ws/a.rs", "called from here"),
+            ("/ws/b.rs", "opposite order: `self.a` (std::sync::Mutex<i32>) acquired while holding `self.b` (std::sync::Mutex<i32>)"),
+            ("/ws/b.rs", "`self.b` (std::sync::Mutex<i32>) acquired here"),
+        ]);
+
+        let b = &result["/ws/b.rs"][0];
+        assert!(b.message.starts_with("ConflictLock: `self.a` (std::sync::Mutex<i32>) acquired while holding `self.b`"));
+        assert_eq!(b.related_information.as_ref().unwrap()[1].location.uri.path(), "/ws/a.rs");
+    }
+
+    ///
+    /// Test the async kinds: blocking outside of a critical section, inversions of async locks
+    /// and await points of a critical section.
+    ///
+    #[test]
+    fn test_async_kinds() {
+        let calls = vec![SuspiciousCall {
+            callchains: vec![RangeInFile::new("/ws/a.rs", 4, 5, 4, 30)],
+            ty: Suspicious::BlockingInAsync,
+            locks: Vec::new(),
+        }];
+        let diags = &suspicious_calls_to_diagnostics(&calls, &mut PositionConverter::new(PositionEncoding::Utf16))["/ws/a.rs"];
+        assert!(diags[0].message.starts_with("BlockingInAsync in async context: "));
+        assert_eq!(diags[0].severity, Some(DiagnosticSeverity::INFORMATION));
+
+        let lock = |expr: &str, line: u32| LockInfo {
+            expr: expr.to_string(),
+            ty: "tokio::sync::Mutex<i32>".to_string(),
+            acquired_at: RangeInFile::new("/ws/a.rs", line, 9, line, 20),
+        };
+        let conflict = LockConflict {
+            sides: [
+                LockOrder { first: lock("a", 2), second: lock("b", 3), callchains: vec![RangeInFile::new("/ws/a.rs", 3, 9, 3, 20)] },
+                LockOrder { first: lock("b", 7), second: lock("a", 8), callchains: vec![RangeInFile::new("/ws/a.rs", 8, 9, 8, 20)] },
+            ],
+        };
+        let diags = &conflicts_to_diagnostics(&[conflict], &mut PositionConverter::new(PositionEncoding::Utf16))["/ws/a.rs"];
+        assert!(diags[0].message.starts_with("AsyncConflictLock: "));
+        assert_eq!(diags[0].code, Some(NumberOrString::String("AsyncConflictLock".to_string())));
+        assert_eq!(diags[0].severity, Some(DiagnosticSeverity::WARNING));
+
+        let areas = vec![HighlightArea {
+            triggers: vec![RangeInFile::new("/ws/a.rs", 2, 9, 2, 20)],
+            ranges: vec![RangeInFile::new("/ws/a.rs", 2, 9, 6, 2)],
+            await_points: vec![RangeInFile::new("/ws/a.rs", 4, 24, 4, 30)],
+            kind: SectionKind::Lock,
+        }];
+        let highlights = &raw_highlight_to_doc_highlights(&areas, &mut PositionConverter::new(PositionEncoding::Utf16))["/ws/a.rs"][0].areas;
+        assert_eq!(highlights.len(), 2);
+        assert_eq!(highlights[1].kind, Some(DocumentHighlightKind::WRITE));
+        assert_eq!(highlights[1].range.start, Position { line: 3, character: 23 });
+    }
+
+    ///
+    /// Test interrupt-free sections are highlighted like lock sections, and locks shared with
+    /// interrupt handlers get their own kind.
+    ///
+    #[test]
+    fn test_embedded_sections() {
+        let areas = vec![HighlightArea {
+            triggers: vec![RangeInFile::new("/fw/main.rs", 3, 5, 3, 27)],
+            ranges: vec![RangeInFile::new("/fw/main.rs", 3, 5, 6, 7)],
+            await_points: Vec::new(),
+            kind: SectionKind::InterruptFree,
+        }];
+        let highlights = &raw_highlight_to_doc_highlights(&areas, &mut PositionConverter::new(PositionEncoding::Utf16))["/fw/main.rs"][0];
+        assert_eq!(highlights.areas[0].range.start, Position { line: 2, character: 4 });
+        assert_eq!(highlights.triggers[0].start.line, 2);
+
+        let calls = vec![SuspiciousCall {
+            callchains: vec![RangeInFile::new("/fw/main.rs", 20, 9, 20, 30)],
+            ty: Suspicious::IsrConflictLock,
+            locks: vec![LockInfo {
+                expr: "BUF".to_string(),
+                ty: "spin::Mutex<[u8; 64]>".to_string(),
+                acquired_at: RangeInFile::new("/fw/main.rs", 20, 9, 20, 30),
+            }],
+        }];
+        let diags = &suspicious_calls_to_diagnostics(&calls, &mut PositionConverter::new(PositionEncoding::Utf16))["/fw/main.rs"];
+        assert_eq!(diags[0].severity, Some(DiagnosticSeverity::WARNING));
+        assert_eq!(diags[0].code, Some(NumberOrString::String("IsrConflictLock".to_string())));
+        assert!(diags[0].message.starts_with("IsrConflictLock in critical section of `BUF` (spin::Mutex<[u8; 64]>): the lock is also taken in an interrupt handler"));
+    }
+
+    ///
+    /// Test compiler errors of the analysis build are reported relative to the analyzed directory.
+    ///
+    #[test]
+    fn test_global_ctx_build_diagnostics() {
+        let mut build = BuildOutput::default();
+        build.push_stdout(r#"{"reason":"compiler-message","message":{"message":"mismatched types","code":{"code":"E0308"},"level":"error","spans":[{"file_name":"src/lib.rs","line_start":3,"line_end":3,"column_start":9,"column_end":12,"is_primary":true}],"rendered":null}}"#);
+        build.push_stdout(r#"{"reason":"compiler-message","message":{"message":"unused import","code":null,"level":"warning","spans":[{"file_name":"src/lib.rs","line_start":1,"line_end":1,"column_start":5,"column_end":8,"is_primary":true}],"rendered":null}}"#);
+
+        let (s1, _) = unbounded();
+        let mut ctx = GlobalCtxt::new(s1);
+        ctx.update_build_output(PathBuf::from("/ws"), &build);
+
+        let diags = ctx.get_diagnoistics().unwrap();
+        let file_diags = diags.get("/ws/src/lib.rs").unwrap();
+        assert_eq!(file_diags.len(), 1);
+        assert_eq!(file_diags[0].severity, Some(DiagnosticSeverity::ERROR));
+        assert_eq!(file_diags[0].range.start, Position { line: 2, character: 8 });
+        assert_eq!(file_diags[0].code, Some(NumberOrString::String("E0308".to_string())));
+
+        ctx.update_build_output(PathBuf::from("/ws"), &BuildOutput::default());
+        assert!(ctx.get_diagnoistics().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_global_ctx_progress() {
+        let (s1, r1) = unbounded();
+        let mut ctx = GlobalCtxt::new(s1);
+
+        // Nothing is sent to clients without work done progress support.
+        ctx.begin_progress(Path::new("/ws"));
+        assert!(r1.try_recv().is_err());
+
+        ctx.supports_progress = true;
+        ctx.begin_progress(Path::new("/ws"));
+        let token = match r1.try_recv().unwrap() {
+            Message::Request(req) => {
+                assert_eq!(req.method, "window/workDoneProgress/create");
+                serde_json::from_value::<WorkDoneProgressCreateParams>(req.params).unwrap().token
+            },
+            msg => panic!("unexpected message {:?}", msg),
+        };
+        assert_eq!(ctx.workspace_for_progress(&token), Some(PathBuf::from("/ws")));
+
+        ctx.report_progress(Path::new("/ws"), "foo (1 crates built)".to_string());
+        ctx.update_from_analysis_result(PathBuf::from("/ws"), AnalysisResult::new(Vec::new(), Vec::new()));
+        ctx.end_progress(Path::new("/ws"), ctx.findings_summary(Path::new("/ws")));
+
+        let progress: Vec<ProgressParams> = r1.try_iter().map(|msg| match msg {
+            Message::Notification(not) => serde_json::from_value(not.params).unwrap(),
+            msg => panic!("unexpected message {:?}", msg),
+        }).collect();
+        assert_eq!(progress.len(), 3);
+        match &progress[2].value {
+            ProgressParamsValue::WorkDone(WorkDoneProgress::End(end)) => {
+                assert_eq!(end.message.as_deref(), Some("0 suspicious call(s) in 0 critical section(s)"));
+            },
+            value => panic!("unexpected progress {:?}", value),
+        }
+        assert!(ctx.workspace_for_progress(&token).is_none());
+    }
+
+    #[test]
+    fn test_global_ctx_clears_stale_diagnostics() {
+        let call = |file: &str| SuspiciousCall {
+            callchains: vec![RangeInFile::new(file, 4, 5, 6, 7)],
+            ty: Suspicious::DoubleLock,
+            locks: Vec::new(),
+        };
+        let (s1, r1) = unbounded();
+        let mut ctx = GlobalCtxt::new(s1);
+        let published = || -> Vec<(String, usize)> {
+            let mut published: Vec<(String, usize)> = r1.try_iter().map(|msg| match msg {
+                Message::Notification(not) => {
+                    let params: PublishDiagnosticsParams = serde_json::from_value(not.params).unwrap();
+                    (params.uri.path().to_string(), params.diagnostics.len())
+                },
+                msg => panic!("unexpected message {:?}", msg),
+            }).collect();
+            published.sort();
+            published
+        };
+
+        ctx.add_workspace(PathBuf::from("/ws1"));
+        ctx.add_workspace(PathBuf::from("/ws2"));
+        ctx.update_from_analysis_result(PathBuf::from("/ws1"), AnalysisResult::new(vec![call("/ws1/a.rs"), call("/ws1/b.rs")], Vec::new()));
+        ctx.update_from_analysis_result(PathBuf::from("/ws2"), AnalysisResult::new(vec![call("/ws2/c.rs")], Vec::new()));
+        ctx.send_diagnoistic();
+        assert_eq!(published(), vec![("/ws1/a.rs".to_string(), 1), ("/ws1/b.rs".to_string(), 1), ("/ws2/c.rs".to_string(), 1)]);
+
+        // The deadlock in b.rs got fixed.
+        ctx.update_from_analysis_result(PathBuf::from("/ws1"), AnalysisResult::new(vec![call("/ws1/a.rs")], Vec::new()));
+        ctx.send_diagnoistic();
+        assert_eq!(published(), vec![("/ws1/a.rs".to_string(), 1), ("/ws1/b.rs".to_string(), 0), ("/ws2/c.rs".to_string(), 1)]);
+
+        ctx.remove_result(Path::new("/ws1"));
+        ctx.send_diagnoistic();
+        assert_eq!(published(), vec![("/ws1/a.rs".to_string(), 0), ("/ws2/c.rs".to_string(), 1)]);
+
+        ctx.remove_workspace(Path::new("/ws2"));
+        ctx.send_diagnoistic();
+        assert_eq!(published(), vec![("/ws2/c.rs".to_string(), 0)]);
+
+        ctx.send_diagnoistic();
+        assert!(published().is_empty());
+    }
+
+    #[test]
+    fn test_global_ctx_hover() {
+        let (s1, r1) = unbounded();
+        let mut ctx = GlobalCtxt::new(s1);
+        let area = HighlightArea {
+            triggers: vec![RangeInFile::new("/ws/a.rs", 2, 9, 2, 20)],
+            ranges: vec![RangeInFile::new("/ws/a.rs", 2, 9, 6, 2)],
+            await_points: Vec::new(),
+            kind: SectionKind::Lock,
+        };
+        let call = SuspiciousCall {
+            callchains: vec![RangeInFile::new("/ws/a.rs", 4, 5, 4, 30)],
+            ty: Suspicious::DoubleLock,
+            locks: Vec::new(),
+        };
+        ctx.update_from_analysis_result(PathBuf::from("/ws"), AnalysisResult::new(vec![call], vec![area]));
+
+        let value = |hover: Option<Hover>| match hover.unwrap().contents {
+            HoverContents::Markup(markup) => markup.value,
+            contents => panic!("unexpected contents {:?}", contents),
+        };
+        assert!(value(ctx.get_hover("/ws/a.rs", &Position { line: 3, character: 10 })).starts_with("**DoubleLock**"));
+        assert!(value(ctx.get_hover("/ws/a.rs", &Position { line: 1, character: 8 })).contains("1 risky call(s) inside"));
+        assert!(ctx.get_hover("/ws/a.rs", &Position { line: 4, character: 0 }).is_none());
+        assert!(ctx.get_hover("/ws/b.rs", &Position { line: 3, character: 10 }).is_none());
+
+        // Clients get a null result where there is nothing to explain.
+        ctx.send_hover(RequestId::from(1), HoverParams {
+            text_document_position_params: lsp_types::TextDocumentPositionParams {
+                text_document: lsp_types::TextDocumentIdentifier { uri: lsp_types::Url::parse("file:///ws/b.rs").unwrap() },
+                position: Position { line: 0, character: 0 },
+            },
+            work_done_progress_params: Default::default(),
+        });
+        match r1.try_recv().unwrap() {
+            Message::Response(resp) => assert_eq!(resp.result, Some(serde_json::Value::Null)),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn test_global_ctx_code_lens_and_reanalyze() {
+        let (s1, r1) = unbounded();
+        let mut ctx = GlobalCtxt::new(s1);
+        ctx.supports_code_lens_refresh = true;
+        ctx.add_workspace(PathBuf::from("/ws"));
+        let area = || HighlightArea {
+            triggers: vec![RangeInFile::new("/ws/a.rs", 2, 9, 2, 20)],
+            ranges: vec![RangeInFile::new("/ws/a.rs", 2, 9, 6, 2)],
+            await_points: Vec::new(),
+            kind: SectionKind::Lock,
+        };
+        ctx.update_from_analysis_result(PathBuf::from("/ws"), AnalysisResult::new(Vec::new(), vec![area()]));
+        match r1.try_recv().unwrap() {
+            Message::Request(req) => assert_eq!(req.method, "workspace/codeLens/refresh"),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        // Only when the sections changed.
+        ctx.update_from_analysis_result(PathBuf::from("/ws"), AnalysisResult::new(Vec::new(), vec![area()]));
+        assert!(r1.try_recv().is_err());
+
+        let titles = |file: &str| -> Vec<String> {
+            ctx.get_code_lenses(Path::new(file)).into_iter().map(|lens| lens.command.unwrap().title).collect()
+        };
+        assert_eq!(titles("/ws/a.rs"), vec!["Reanalyze crate", "critical section · 5 lines · 0 blocking calls"]);
+        assert_eq!(titles("/ws/b.rs"), vec!["Reanalyze crate"]);
+        assert!(titles("/other/a.rs").is_empty());
+
+        let command = |arguments: Vec<serde_json::Value>| ExecuteCommandParams {
+            command: REANALYZE_COMMAND.to_string(),
+            arguments,
+            work_done_progress_params: Default::default(),
+        };
+        assert_eq!(ctx.reanalyze_root(&command(vec![serde_json::json!("file:///ws/a.rs")])), Ok(PathBuf::from("/ws")));
+        assert_eq!(
+            ctx.reanalyze_root(&command(vec![serde_json::json!("file:///other/a.rs")])),
+            Err("/other/a.rs is not in any workspace folder".to_string())
+        );
+        assert!(ctx.reanalyze_root(&command(Vec::new())).is_err());
+    }
+
+    #[test]
+    fn test_global_ctx_code_actions() -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(".tmp")?;
+        let file = fs::canonicalize(".tmp")?.join("_test_code_actions.rs");
+        fs::write(&file, "fn f(&self) {\n    let g = self.a.lock().unwrap();\n    self.rx.recv().unwrap();\n}\n")?;
+        let file = file.to_string_lossy().to_string();
+        let (s1, _r1) = unbounded();
+        let mut ctx = GlobalCtxt::new(s1);
+        let call = SuspiciousCall {
+            callchains: vec![RangeInFile::new(file.clone(), 3, 5, 3, 28)],
+            ty: Suspicious::ChRecv,
+            locks: vec![LockInfo {
+                expr: "self.a".to_string(),
+                ty: "std::sync::Mutex<i32>".to_string(),
+                acquired_at: RangeInFile::new(file.clone(), 2, 13, 2, 26),
+            }],
+        };
+        ctx.update_from_analysis_result(PathBuf::from("/ws"), AnalysisResult::new(vec![call], Vec::new()));
+
+        // Fixes are attached to the diagnostics the server published.
+        let diagnostics = ctx.get_diagnoistics().unwrap().remove(&file).unwrap();
+        let actions = ctx.get_code_actions(&file, &diagnostics);
+        let titles: Vec<&str> = actions.iter().map(|a| a.title.as_str()).collect();
+        assert_eq!(titles, vec!["Drop the guard of `self.a` before this call", "End the scope of the guard of `self.a` before this call"]);
+        assert_eq!(actions[0].diagnostics, Some(diagnostics.clone()));
+
+        let mut other = diagnostics[0].clone();
+        other.range.start.line = 0;
+        assert!(ctx.get_code_actions(&file, &[other]).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_global_ctx_status() {
+        let (s1, r1) = unbounded();
+        let mut ctx = GlobalCtxt::new(s1);
+        ctx.add_workspace(PathBuf::from("/ws1"));
+        ctx.add_workspace(PathBuf::from("/ws2"));
+
+        let mut result = AnalysisResult::new(Vec::new(), Vec::new());
+        result.metadata = Some(Metadata { commit: Some("1a2b3c4".to_string()), duration_ms: 2000, ..Metadata::default() });
+        ctx.update_from_analysis_result(PathBuf::from("/ws1/app"), result);
+
+        ctx.send_status(RequestId::from(7));
+        match r1.try_recv().unwrap() {
+            Message::Response(resp) => assert_eq!(
+                resp.result.unwrap(),
+                "/ws1/app: 0 suspicious call(s) in 0 critical section(s)\n  commit 1a2b3c4, took 2.0 s\n/ws2: not analyzed yet"
+            ),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+}
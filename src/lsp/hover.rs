@@ -0,0 +1,124 @@
+//! Markdown shown when hovering a suspicious call or the start of a critical section.
+
+use std::path::Path;
+
+use super::lockbud_ty::{AnalysisResult, HighlightArea, RangeInFile, SuspiciousCall};
+
+/// The kind, locks, call chain and fix of `call`.
+pub fn call_markdown(root: &Path, result: &AnalysisResult, call: &SuspiciousCall) -> String {
+    let mut sections = vec![format!("**{}**: {}", call.ty.name(), call.description())];
+    if !call.locks.is_empty() {
+        let locks: Vec<String> = call
+            .locks
+            .iter()
+            .map(|lock| format!("- {} acquired at {}", lock.describe(), link(root, &lock.acquired_at)))
+            .collect();
+        sections.push(format!("Locks held:\n{}", locks.join("\n")));
+    }
+    let chain: Vec<String> = call
+        .callchains
+        .iter()
+        .enumerate()
+        .map(|(i, r)| format!("{}. {}", i + 1, link(root, r)))
+        .collect();
+    sections.push(format!("Call chain:\n{}", chain.join("\n")));
+    sections.push(format!("Fix: {}", call.ty.remediation()));
+    sections.extend(provenance(result));
+    sections.join("\n\n")
+}
+
+/// The extent of `area` and the suspicious calls made from it.
+pub fn section_markdown(root: &Path, result: &AnalysisResult, area: &HighlightArea) -> String {
+    let mut sections = vec![format!("**Critical section** held by {}", area.kind.describe())];
+    let extent: Vec<String> = area
+        .ranges
+        .iter()
+        .map(|r| format!("- lines {}-{} of {}", r.start.line, r.end.line, link(root, r)))
+        .collect();
+    sections.push(extent.join("\n"));
+    if !area.await_points.is_empty() {
+        sections.push(format!("Held across {} `.await`(s)", area.await_points.len()));
+    }
+
+    let calls = area.calls_inside(&result.calls);
+    if calls.is_empty() {
+        sections.push("No risky call inside".to_string());
+    } else {
+        let lines: Vec<String> = calls
+            .iter()
+            .filter_map(|call| Some(format!("- {} at {}", call.ty.name(), link(root, call.callchains.last()?))))
+            .collect();
+        sections.push(format!("{} risky call(s) inside:\n{}", calls.len(), lines.join("\n")));
+    }
+    sections.extend(provenance(result));
+    sections.join("\n\n")
+}
+
+fn provenance(result: &AnalysisResult) -> Option<String> {
+    result.metadata.as_ref().map(|metadata| format!("_{}_", metadata.summary()))
+}
+
+/// `file:line` relative to `root`, linked to the position if the file has a URI.
+fn link(root: &Path, r: &RangeInFile) -> String {
+    let path = Path::new(&r.file);
+    let text = format!("{}:{}", path.strip_prefix(root).unwrap_or(path).display(), r.start.line);
+    match lsp_types::Url::from_file_path(path) {
+        Ok(uri) => format!("[{}]({}#L{},{})", text, uri, r.start.line, r.start.col),
+        Err(()) => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lsp::lockbud_ty::{LockInfo, Metadata, SectionKind, Suspicious};
+
+    use super::*;
+
+    fn call(ty: Suspicious, from_line: u32) -> SuspiciousCall {
+        SuspiciousCall {
+            callchains: vec![RangeInFile::new("/ws/src/a.rs", from_line, 9, from_line, 20), RangeInFile::new("/ws/src/b.rs", 7, 5, 7, 30)],
+            ty,
+            locks: vec![LockInfo {
+                expr: "self.a".to_string(),
+                ty: "std::sync::Mutex<i32>".to_string(),
+                acquired_at: RangeInFile::new("/ws/src/a.rs", 2, 9, 2, 20),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_call_markdown() {
+        let mut result = AnalysisResult::new(Vec::new(), Vec::new());
+        result.metadata = Some(Metadata { commit: Some("1a2b3c4".to_string()), ..Metadata::default() });
+
+        let markdown = call_markdown(Path::new("/ws"), &result, &call(Suspicious::ChRecv, 3));
+        assert_eq!(
+            markdown,
+            "**ChRecv**: receiving from a channel blocks until a message arrives\n\n\
+             Locks held:\n- `self.a` (std::sync::Mutex<i32>) acquired at [src/a.rs:2](file:///ws/src/a.rs#L2,9)\n\n\
+             Call chain:\n1. [src/a.rs:3](file:///ws/src/a.rs#L3,9)\n2. [src/b.rs:7](file:///ws/src/b.rs#L7,5)\n\n\
+             Fix: Receive before locking or after the guard is dropped, or use `try_recv`.\n\n\
+             _commit 1a2b3c4, took 0.0 s_"
+        );
+    }
+
+    #[test]
+    fn test_section_markdown_counts_calls() {
+        let area = HighlightArea {
+            triggers: vec![RangeInFile::new("/ws/src/a.rs", 2, 9, 2, 20)],
+            ranges: vec![RangeInFile::new("/ws/src/a.rs", 2, 9, 5, 6)],
+            await_points: Vec::new(),
+            kind: SectionKind::Lock,
+        };
+        let result = AnalysisResult::new(
+            vec![call(Suspicious::ChRecv, 3), call(Suspicious::DoubleLock, 4), call(Suspicious::ChSend, 9)],
+            Vec::new(),
+        );
+
+        let markdown = section_markdown(Path::new("/ws"), &result, &area);
+        assert!(markdown.starts_with("**Critical section** held by a lock guard\n\n- lines 2-5 of [src/a.rs:2]"));
+        assert!(markdown.ends_with(
+            "2 risky call(s) inside:\n- ChRecv at [src/b.rs:7](file:///ws/src/b.rs#L7,5)\n- DoubleLock at [src/b.rs:7](file:///ws/src/b.rs#L7,5)"
+        ));
+    }
+}
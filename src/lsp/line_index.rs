@@ -0,0 +1,202 @@
+//! Conversion of lockbud's ranges, whose columns count chars, to LSP positions,
+//! whose columns count code units of the negotiated encoding.
+
+use std::{collections::HashMap, fs};
+
+use lsp_types::{Position, Range};
+use serde_json::Value;
+
+use super::lockbud_ty::{LineCol, RangeInFile};
+
+/// Unit of the columns of LSP positions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PositionEncoding {
+    Utf8,
+    /// The only encoding before LSP 3.17 and the default since.
+    #[default]
+    Utf16,
+    Utf32,
+}
+
+impl PositionEncoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PositionEncoding::Utf8 => "utf-8",
+            PositionEncoding::Utf16 => "utf-16",
+            PositionEncoding::Utf32 => "utf-32",
+        }
+    }
+
+    /// The first of the client's `general.positionEncodings` the server supports, in the
+    /// client's order of preference, or UTF-16 for clients that do not send any.
+    pub fn negotiate(client_capabilities: &Value) -> PositionEncoding {
+        let offered = match client_capabilities["general"]["positionEncodings"].as_array() {
+            Some(offered) => offered,
+            None => return PositionEncoding::Utf16,
+        };
+        for name in offered.iter().filter_map(Value::as_str) {
+            match name {
+                "utf-8" => return PositionEncoding::Utf8,
+                "utf-16" => return PositionEncoding::Utf16,
+                "utf-32" => return PositionEncoding::Utf32,
+                _ => {},
+            }
+        }
+        PositionEncoding::Utf16
+    }
+
+    fn len(&self, c: char) -> u32 {
+        match self {
+            PositionEncoding::Utf8 => c.len_utf8() as u32,
+            PositionEncoding::Utf16 => c.len_utf16() as u32,
+            PositionEncoding::Utf32 => 1,
+        }
+    }
+}
+
+/// The lines of one file.
+pub struct LineIndex {
+    text: String,
+    /// Byte offset of the start of every line.
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(text: String) -> Self {
+        let mut line_starts = vec![0];
+        line_starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+        LineIndex { text, line_starts }
+    }
+
+    fn line(&self, line: usize) -> Option<&str> {
+        let start = *self.line_starts.get(line)?;
+        let end = self.line_starts.get(line + 1).copied().unwrap_or(self.text.len());
+        Some(self.text[start..end].trim_end_matches(&['\n', '\r'][..]))
+    }
+
+    /// Converts a 1 based position with a char column. Columns past the end of the
+    /// line, and lines past the end of the file, count one unit per missing char.
+    pub fn position(&self, pos: LineCol, encoding: PositionEncoding) -> Position {
+        let line = pos.line.saturating_sub(1);
+        let chars = pos.col.saturating_sub(1);
+        let text = self.line(line as usize).unwrap_or("");
+        let mut character = 0;
+        let mut counted = 0;
+        for c in text.chars().take(chars as usize) {
+            character += encoding.len(c);
+            counted += 1;
+        }
+        Position { line, character: character + (chars - counted) }
+    }
+
+    /// The text of the first line of the range from `start` to `end`.
+    pub fn first_line(&self, start: LineCol, end: LineCol) -> String {
+        let text = self.line(start.line.saturating_sub(1) as usize).unwrap_or("");
+        let chars = text.chars().skip(start.col.saturating_sub(1) as usize);
+        match end.line == start.line {
+            true => chars.take(end.col.saturating_sub(start.col) as usize).collect(),
+            false => chars.collect(),
+        }
+    }
+}
+
+/// Whether `pos` lies within `range`, both ends included.
+pub fn contains(range: &Range, pos: &Position) -> bool {
+    range.start <= *pos && *pos <= range.end
+}
+
+/// Converts the ranges of one analysis result, reading each file once. Ranges in
+/// files that cannot be read are converted as if they were ASCII.
+pub struct PositionConverter {
+    encoding: PositionEncoding,
+    files: HashMap<String, Option<LineIndex>>,
+}
+
+impl PositionConverter {
+    pub fn new(encoding: PositionEncoding) -> Self {
+        PositionConverter { encoding, files: HashMap::new() }
+    }
+
+    pub fn range(&mut self, r: &RangeInFile) -> Range {
+        Range { start: self.position(&r.file, r.start), end: self.position(&r.file, r.end) }
+    }
+
+    pub fn position(&mut self, file: &str, pos: LineCol) -> Position {
+        let encoding = self.encoding;
+        match self.index(file) {
+            Some(index) => index.position(pos, encoding),
+            None => Position { line: pos.line.saturating_sub(1), character: pos.col.saturating_sub(1) },
+        }
+    }
+
+    /// The source of the first line of `r`, `None` if the file cannot be read.
+    pub fn first_line(&mut self, r: &RangeInFile) -> Option<String> {
+        self.index(&r.file).map(|index| index.first_line(r.start, r.end))
+    }
+
+    fn index(&mut self, file: &str) -> Option<&LineIndex> {
+        self.files
+            .entry(file.to_string())
+            .or_insert_with(|| fs::read_to_string(file).ok().map(LineIndex::new))
+            .as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn at(line: u32, col: u32) -> LineCol {
+        LineCol { line, col }
+    }
+
+    #[test]
+    fn test_line_index_position() {
+        // "é" is 2 bytes and 1 UTF-16 unit, "🔒" 4 bytes and 2 UTF-16 units.
+        let index = LineIndex::new("fn f() {}\r\n\tlet é = 🔒.lock();\n".to_string());
+        assert_eq!(index.position(at(1, 4), PositionEncoding::Utf16), Position { line: 0, character: 3 });
+
+        // `lock` is the 12th char of line 2.
+        assert_eq!(index.position(at(2, 12), PositionEncoding::Utf8), Position { line: 1, character: 15 });
+        assert_eq!(index.position(at(2, 12), PositionEncoding::Utf16), Position { line: 1, character: 12 });
+        assert_eq!(index.position(at(2, 12), PositionEncoding::Utf32), Position { line: 1, character: 11 });
+
+        // Past the end of the line or the file.
+        assert_eq!(index.position(at(1, 12), PositionEncoding::Utf16), Position { line: 0, character: 11 });
+        assert_eq!(index.position(at(9, 3), PositionEncoding::Utf16), Position { line: 8, character: 2 });
+    }
+
+    #[test]
+    fn test_position_converter_reads_files() -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(".tmp")?;
+        let file = fs::canonicalize(".tmp")?.join("_test_line_index.rs");
+        fs::write(&file, "let ü = m.lock();\n")?;
+        let file = file.to_string_lossy().to_string();
+
+        let mut converter = PositionConverter::new(PositionEncoding::Utf8);
+        let range = converter.range(&RangeInFile::new(file.clone(), 1, 9, 1, 17));
+        assert_eq!(range.start, Position { line: 0, character: 9 });
+        assert_eq!(range.end, Position { line: 0, character: 17 });
+
+        let range = converter.range(&RangeInFile::new("/no/such/file.rs", 1, 9, 1, 17));
+        assert_eq!(range.start, Position { line: 0, character: 8 });
+
+        assert_eq!(converter.first_line(&RangeInFile::new(file.clone(), 1, 9, 1, 17)).as_deref(), Some("m.lock()"));
+        assert_eq!(converter.first_line(&RangeInFile::new(file, 1, 5, 3, 1)).as_deref(), Some("ü = m.lock();"));
+        assert_eq!(converter.first_line(&RangeInFile::new("/no/such/file.rs", 1, 9, 1, 17)), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_negotiate_position_encoding() {
+        let client = |encodings: Value| json!({ "general": { "positionEncodings": encodings } });
+        assert_eq!(PositionEncoding::negotiate(&json!({})), PositionEncoding::Utf16);
+        assert_eq!(PositionEncoding::negotiate(&client(json!(["utf-8", "utf-16"]))), PositionEncoding::Utf8);
+        assert_eq!(PositionEncoding::negotiate(&client(json!(["wtf-7", "utf-32"]))), PositionEncoding::Utf32);
+        assert_eq!(PositionEncoding::negotiate(&client(json!(["wtf-7"]))), PositionEncoding::Utf16);
+    }
+}
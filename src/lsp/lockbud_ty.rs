@@ -1,51 +1,519 @@
 // FIXME: this file should be synced with luckbud's src/cs/diagnostics.rs file.
 // Ideally this project should either put together with luckbud or add luckbud as dependency at Cargo.toml, or extract interaces as independent crate.
+// Until then the format is versioned by `SCHEMA_VERSION`, and `deadlock-lsp schema` prints the JSON Schema lockbud's
+// output has to follow, checked in at schema/analysis_result.schema.json.
 
-use std::{error::Error, fs::File, io::BufReader};
+use std::{error::Error, fmt, fs::File, io::BufReader, path::Path};
 
+use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
+use serde_json::Value;
 
+/// Version of the result format. Bump it on every format change and append
+/// the upgrade from the previous version to `MIGRATIONS`.
+pub const SCHEMA_VERSION: u32 = 7;
 
+/// `MIGRATIONS[v]` upgrades a result of schema version `v` to `v + 1`.
+const MIGRATIONS: &[fn(&mut Value)] = &[
+    // Results written before lockbud recorded a version have the same shape as version 1.
+    |_| {},
+    // Version 2 added the optional `metadata`.
+    |_| {},
+    // Version 3 added `locks` to calls, which defaults to none.
+    |_| {},
+    // Version 4 added `conflicts`, which defaults to none. Older ConflictLock calls stay one sided.
+    |_| {},
+    // Version 5 added the RwLock, Once, Barrier and join kinds to `Suspicious`.
+    |_| {},
+    // Version 6 added the async kinds and `await_points` of critical sections, which defaults to none.
+    |_| {},
+    // Version 7 added the `kind` of critical sections, which defaults to a lock, and IsrConflictLock.
+    |_| {},
+];
 
-#[derive(Hash, Eq, PartialEq, Copy, Clone, Serialize, Deserialize, Debug)]
+
+/// Kind of a suspicious call, written by lockbud as its variant name.
+#[derive(Hash, Eq, PartialEq, Clone, Debug)]
 pub enum Suspicious {
     ChSend,
     ChRecv,
     CondVarWait,
     DoubleLock,
-    ConflictLock
+    ConflictLock,
+    /// An `RwLock` read lock taken again while the same thread holds a read guard,
+    /// which blocks as soon as a writer is waiting.
+    RwLockRecursiveRead,
+    /// An `RwLock` write lock taken while the same thread holds a read guard.
+    RwLockReadThenWrite,
+    /// A `parking_lot` upgradable read guard upgraded while another upgradable or read guard is held.
+    RwLockUpgrade,
+    /// `Once::call_once` or `OnceLock::get_or_init` re-entered from its own initializer.
+    OnceReentrant,
+    BarrierWait,
+    ThreadJoin,
+    /// The guard of a blocking lock, e.g. `std::sync::MutexGuard`, held across an `.await`.
+    GuardAcrossAwait,
+    /// Async locks, e.g. `tokio::sync::Mutex`, also acquired in the opposite order.
+    AsyncConflictLock,
+    /// A blocking call like `std::thread::sleep`, `Receiver::recv` or file I/O in an `async fn`,
+    /// reported whether or not a lock is held.
+    BlockingInAsync,
+    /// A lock, typically a spin lock, taken both in thread context and in an interrupt handler.
+    IsrConflictLock,
+    /// A kind this server does not know yet, kept so that newer lockbud builds still load.
+    Unknown(String),
+}
+
+impl Suspicious {
+    pub const KNOWN: &'static [Suspicious] = &[
+        Suspicious::ChSend,
+        Suspicious::ChRecv,
+        Suspicious::CondVarWait,
+        Suspicious::DoubleLock,
+        Suspicious::ConflictLock,
+        Suspicious::RwLockRecursiveRead,
+        Suspicious::RwLockReadThenWrite,
+        Suspicious::RwLockUpgrade,
+        Suspicious::OnceReentrant,
+        Suspicious::BarrierWait,
+        Suspicious::ThreadJoin,
+        Suspicious::GuardAcrossAwait,
+        Suspicious::AsyncConflictLock,
+        Suspicious::BlockingInAsync,
+        Suspicious::IsrConflictLock,
+    ];
+
+    pub fn name(&self) -> &str {
+        match self {
+            Suspicious::ChSend => "ChSend",
+            Suspicious::ChRecv => "ChRecv",
+            Suspicious::CondVarWait => "CondVarWait",
+            Suspicious::DoubleLock => "DoubleLock",
+            Suspicious::ConflictLock => "ConflictLock",
+            Suspicious::RwLockRecursiveRead => "RwLockRecursiveRead",
+            Suspicious::RwLockReadThenWrite => "RwLockReadThenWrite",
+            Suspicious::RwLockUpgrade => "RwLockUpgrade",
+            Suspicious::OnceReentrant => "OnceReentrant",
+            Suspicious::BarrierWait => "BarrierWait",
+            Suspicious::ThreadJoin => "ThreadJoin",
+            Suspicious::GuardAcrossAwait => "GuardAcrossAwait",
+            Suspicious::AsyncConflictLock => "AsyncConflictLock",
+            Suspicious::BlockingInAsync => "BlockingInAsync",
+            Suspicious::IsrConflictLock => "IsrConflictLock",
+            Suspicious::Unknown(name) => name,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Suspicious {
+        match Suspicious::KNOWN.iter().find(|k| k.name() == name) {
+            Some(known) => known.clone(),
+            None => Suspicious::Unknown(name.to_string()),
+        }
+    }
+
+    /// Where the call is, lockbud reports blocking in async code also outside of critical sections.
+    pub fn context(&self) -> &'static str {
+        match self {
+            Suspicious::BlockingInAsync => "async context",
+            _ => "critical section",
+        }
+    }
+
+    /// What makes the call suspicious, completing "<kind> in <context>: ".
+    pub fn description(&self) -> &'static str {
+        match self {
+            Suspicious::ChSend => "sending on a channel may block until there is room",
+            Suspicious::ChRecv => "receiving from a channel blocks until a message arrives",
+            Suspicious::CondVarWait => "waiting on a condition variable blocks until it is notified",
+            Suspicious::DoubleLock => "the lock is acquired again while it is held, which deadlocks or panics",
+            Suspicious::ConflictLock => "the locks are also acquired in the opposite order, which may deadlock",
+            Suspicious::RwLockRecursiveRead => "the read lock is acquired again while it is held, which deadlocks once a writer waits",
+            Suspicious::RwLockReadThenWrite => "the write lock is acquired while a read guard of the same lock is held, which deadlocks",
+            Suspicious::RwLockUpgrade => "the upgrade waits for the other readers of the lock, which deadlocks if this thread holds one",
+            Suspicious::OnceReentrant => "the initializer re-enters its own once cell, which deadlocks or panics",
+            Suspicious::BarrierWait => "waiting on a barrier blocks until all threads arrive, which may need the held lock",
+            Suspicious::ThreadJoin => "joining a thread blocks until it exits, which may need the held lock",
+            Suspicious::GuardAcrossAwait => "the guard is held across an `.await`, other tasks locking it block the runtime's worker thread",
+            Suspicious::AsyncConflictLock => "the async locks are also acquired in the opposite order, which may deadlock the tasks",
+            Suspicious::BlockingInAsync => "the call blocks the runtime's worker thread, use an async version or `spawn_blocking`",
+            Suspicious::IsrConflictLock => "the lock is also taken in an interrupt handler, which deadlocks if the interrupt fires while it is held",
+            Suspicious::Unknown(_) => "reported by a newer lockbud, update deadlock-lsp for details",
+        }
+    }
+
+    /// How such a call is usually fixed.
+    pub fn remediation(&self) -> &'static str {
+        match self {
+            Suspicious::ChSend => "Send after the guard is dropped, or give the channel enough capacity.",
+            Suspicious::ChRecv => "Receive before locking or after the guard is dropped, or use `try_recv`.",
+            Suspicious::CondVarWait => "Hold only the mutex of the condition variable while waiting.",
+            Suspicious::DoubleLock => "Drop the first guard before locking again, or pass the guard on instead of the lock.",
+            Suspicious::ConflictLock => "Acquire the locks in the same order everywhere, or merge them into one lock.",
+            Suspicious::RwLockRecursiveRead => "Reuse the read guard that is held instead of reading again.",
+            Suspicious::RwLockReadThenWrite => "Drop the read guard before taking the write lock.",
+            Suspicious::RwLockUpgrade => "Drop the other read guards first, or take the write lock directly.",
+            Suspicious::OnceReentrant => "Do not touch the once cell from its own initializer.",
+            Suspicious::BarrierWait => "Drop the guard before waiting on the barrier.",
+            Suspicious::ThreadJoin => "Drop the guard before joining the thread.",
+            Suspicious::GuardAcrossAwait => "Drop the guard before the `.await`, or use an async mutex like `tokio::sync::Mutex`.",
+            Suspicious::AsyncConflictLock => "Acquire the async locks in the same order everywhere.",
+            Suspicious::BlockingInAsync => "Use the async counterpart of the call, or move it into `spawn_blocking`.",
+            Suspicious::IsrConflictLock => "Take the lock in thread context only with interrupts disabled, or do not share it with the handler.",
+            Suspicious::Unknown(_) => "See the lockbud documentation of the kind.",
+        }
+    }
+}
+
+impl Serialize for Suspicious {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Suspicious {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(Suspicious::from_name(&name))
+    }
+}
+
+impl JsonSchema for Suspicious {
+    fn schema_name() -> String {
+        "Suspicious".to_string()
+    }
+
+    fn json_schema(_: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        schemars::schema::SchemaObject {
+            instance_type: Some(schemars::schema::InstanceType::String.into()),
+            enum_values: Some(Suspicious::KNOWN.iter().map(|k| k.name().into()).collect()),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+/// A position as rustc reports it: the line and the column, counted in chars, both 1 based.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LineCol {
+    pub line: u32,
+    pub col: u32,
+}
+
+/// A range of source code, written as `[file, start line, start column, end line, end column]`.
+/// The columns count chars, so converting them to LSP positions needs the file, see `lsp::line_index`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RangeInFile {
+    pub file: String,
+    pub start: LineCol,
+    pub end: LineCol,
+}
+
+impl RangeInFile {
+    pub fn new(file: impl Into<String>, start_line: u32, start_col: u32, end_line: u32, end_col: u32) -> Self {
+        RangeInFile {
+            file: file.into(),
+            start: LineCol { line: start_line, col: start_col },
+            end: LineCol { line: end_line, col: end_col },
+        }
+    }
+}
+
+impl fmt::Display for RangeInFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}-{}:{}", self.file, self.start.line, self.start.col, self.end.line, self.end.col)
+    }
+}
+
+type RawRange = (String, u32, u32, u32, u32);
+
+impl Serialize for RangeInFile {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (&self.file, self.start.line, self.start.col, self.end.line, self.end.col).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for RangeInFile {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (file, start_line, start_col, end_line, end_col) = RawRange::deserialize(deserializer)?;
+        Ok(RangeInFile::new(file, start_line, start_col, end_line, end_col))
+    }
 }
 
-// filename, start line & col, end line & col
-pub type RangeInFile = (String, u32, u32, u32, u32);
+impl JsonSchema for RangeInFile {
+    fn schema_name() -> String {
+        "RangeInFile".to_string()
+    }
+
+    // Inlined like the tuple it is written as.
+    fn is_referenceable() -> bool {
+        false
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        RawRange::json_schema(gen)
+    }
+}
 
 
-#[derive(Debug, Serialize, Deserialize, Hash, PartialEq, Eq)]
+/// A lock involved in a finding.
+#[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq, JsonSchema)]
+pub struct LockInfo {
+    /// Source expression of the lock, e.g. `self.inner.state`.
+    pub expr: String,
+    /// Type of the lock, e.g. `std::sync::Mutex<T>` or `parking_lot::RwLock<T>`.
+    pub ty: String,
+    /// Where the lock was acquired.
+    pub acquired_at: RangeInFile,
+}
+
+#[derive(Debug, Serialize, Deserialize, Hash, PartialEq, Eq, JsonSchema)]
 pub struct SuspiciousCall {
+    /// Calls leading from the critical section to the suspicious one, which is last.
+    /// Ranges are (file, start line, start column, end line, end column), 1 based.
     pub callchains: Vec<RangeInFile>,
     pub ty: Suspicious,
+    /// Locks held at the suspicious call, in acquisition order.
+    #[serde(default)]
+    pub locks: Vec<LockInfo>,
+}
+
+/// One code path of a lock-order inversion: `second` is acquired while `first` is held.
+#[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq, JsonSchema)]
+pub struct LockOrder {
+    pub first: LockInfo,
+    pub second: LockInfo,
+    /// Calls from the critical section of `first` to the acquisition of `second`, which is last.
+    pub callchains: Vec<RangeInFile>,
 }
 
-#[derive(Debug, Serialize, Deserialize,  PartialEq, Eq)]
+/// Two code paths taking the same two locks in opposite order.
+#[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq, JsonSchema)]
+pub struct LockConflict {
+    pub sides: [LockOrder; 2],
+}
+
+impl SuspiciousCall {
+    /// Why the call is suspicious, refined by the locks held.
+    pub fn description(&self) -> &'static str {
+        match self.ty {
+            // A spin lock never parks the thread, it burns a core instead.
+            Suspicious::DoubleLock if self.locks.iter().any(LockInfo::is_spin) => {
+                "the spin lock is acquired again while it is held, the thread spins forever"
+            },
+            _ => self.ty.description(),
+        }
+    }
+}
+
+impl LockInfo {
+    /// Names the lock in messages, e.g. "`self.state` (std::sync::Mutex<State>)".
+    pub fn describe(&self) -> String {
+        format!("`{}` ({})", self.expr, self.ty)
+    }
+
+    /// Locks of the `spin` crate, which busy-wait instead of blocking.
+    pub fn is_spin(&self) -> bool {
+        self.ty.starts_with("spin::")
+    }
+
+    /// Whether waiting for the lock suspends the task instead of blocking the thread.
+    pub fn is_async(&self) -> bool {
+        ["tokio::sync::", "futures::lock::", "async_std::sync::"].iter().any(|p| self.ty.starts_with(p))
+    }
+}
+
+/// What makes a region of code a critical section.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SectionKind {
+    /// A lock guard is alive.
+    #[default]
+    Lock,
+    /// Interrupts are disabled by `cortex_m::interrupt::free`.
+    InterruptFree,
+    /// The closure of `critical_section::with`.
+    CriticalSection,
+}
+
+#[derive(Debug, Serialize, Deserialize,  PartialEq, Eq, JsonSchema)]
 pub struct HighlightArea {
     pub triggers: Vec<RangeInFile>,
     // filename, start line & col, end line & col
-    pub ranges: Vec<RangeInFile>
+    pub ranges: Vec<RangeInFile>,
+    /// `.await`s inside the section, where the task may be suspended with the lock held.
+    #[serde(default)]
+    pub await_points: Vec<RangeInFile>,
+    #[serde(default)]
+    pub kind: SectionKind,
+}
+
+impl SectionKind {
+    /// What holds the section, e.g. for hover text.
+    pub fn describe(&self) -> &'static str {
+        match self {
+            SectionKind::Lock => "a lock guard",
+            SectionKind::InterruptFree => "`interrupt::free`",
+            SectionKind::CriticalSection => "`critical_section::with`",
+        }
+    }
+}
+
+impl HighlightArea {
+    /// Whether `r` lies within one of the ranges of the section.
+    pub fn contains(&self, r: &RangeInFile) -> bool {
+        self.ranges.iter().any(|range| range.file == r.file && range.start <= r.start && r.end <= range.end)
+    }
+
+    /// The calls of `calls` made from the section. Call chains start in the section they were found in.
+    pub fn calls_inside<'a>(&self, calls: &'a [SuspiciousCall]) -> Vec<&'a SuspiciousCall> {
+        calls.iter().filter(|call| matches!(call.callchains.first(), Some(first) if self.contains(first))).collect()
+    }
+
+    /// Number of lines the ranges of the section span.
+    pub fn lines(&self) -> u32 {
+        self.ranges.iter().map(|r| r.end.line.saturating_sub(r.start.line) + 1).sum()
+    }
+}
+
+/// Where a result comes from, filled in by the server once the run finished.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct Metadata {
+    /// `git rev-parse HEAD` of the analyzed directory, suffixed with `-dirty` for uncommitted changes.
+    pub commit: Option<String>,
+    /// `rustc -V` in the analyzed directory.
+    pub toolchain: Option<String>,
+    pub lockbud_version: Option<String>,
+    /// Arguments of the analysis `cargo` invocation.
+    pub cargo_args: Vec<String>,
+    /// Crates lockbud analyzed.
+    pub crates: Vec<String>,
+    /// Seconds since the Unix epoch.
+    pub finished_at: u64,
+    pub duration_ms: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
 pub struct AnalysisResult {
+    /// `SCHEMA_VERSION` of the writer, results without it are read as version 0.
+    pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Metadata>,
     pub calls: Vec<SuspiciousCall>,
+    /// Lock-order inversions, described from both sides.
+    #[serde(default)]
+    pub conflicts: Vec<LockConflict>,
     pub critical_sections: Vec<HighlightArea>
 }
 
+impl Metadata {
+    /// One line naming the run, e.g. for status and hover text.
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(commit) = &self.commit {
+            parts.push(format!("commit {}", commit));
+        }
+        if let Some(toolchain) = &self.toolchain {
+            parts.push(toolchain.clone());
+        }
+        if let Some(lockbud) = &self.lockbud_version {
+            parts.push(format!("lockbud {}", lockbud));
+        }
+        if !self.crates.is_empty() {
+            parts.push(format!("crates {}", self.crates.join(", ")));
+        }
+        parts.push(format!("took {:.1} s", self.duration_ms as f64 / 1000.0));
+        parts.join(", ")
+    }
+}
+
 impl AnalysisResult {
 
+    pub fn new(calls: Vec<SuspiciousCall>, critical_sections: Vec<HighlightArea>) -> Self {
+        AnalysisResult { schema_version: SCHEMA_VERSION, metadata: None, calls, conflicts: Vec::new(), critical_sections }
+    }
+
     pub fn from_file(p: &str)->Result<AnalysisResult, Box<dyn Error>> {
         let file = File::open(p)?;
         let reader = BufReader::new(file);
-        let r = serde_json::from_reader(reader)?;
-        Ok(r)
+        Self::from_value(serde_json::from_reader(reader)?)
+    }
+
+    /// Migrates a result of any supported schema version to the current one and validates it.
+    pub fn from_value(mut value: Value) -> Result<AnalysisResult, Box<dyn Error>> {
+        let version = match value.get("schema_version") {
+            Some(v) => v.as_u64().ok_or_else(|| format!("schema_version {} is not a number", v))?,
+            None => 0,
+        };
+        if version > SCHEMA_VERSION as u64 {
+            return Err(format!(
+                "schema version {} is newer than the supported version {}, update deadlock-lsp",
+                version, SCHEMA_VERSION
+            ).into());
+        }
+        if !value.is_object() {
+            return Err("the analysis result is not a JSON object".into());
+        }
+        for migrate in &MIGRATIONS[version as usize..] {
+            migrate(&mut value);
+        }
+        value["schema_version"] = SCHEMA_VERSION.into();
+
+        let result: AnalysisResult = serde_json::from_value(value)?;
+        result.validate()?;
+        Ok(result)
+    }
+
+    /// Checks what the server relies on but serde cannot: non empty call chains and
+    /// areas, and 1 based ranges that do not end before they start.
+    pub fn validate(&self) -> Result<(), String> {
+        for (i, call) in self.calls.iter().enumerate() {
+            if call.callchains.is_empty() {
+                return Err(format!("calls[{}].callchains is empty", i));
+            }
+            for (j, r) in call.callchains.iter().enumerate() {
+                validate_range(r, &format!("calls[{}].callchains[{}]", i, j))?;
+            }
+            for (j, lock) in call.locks.iter().enumerate() {
+                validate_lock(lock, &format!("calls[{}].locks[{}]", i, j))?;
+            }
+        }
+        for (i, conflict) in self.conflicts.iter().enumerate() {
+            for (j, side) in conflict.sides.iter().enumerate() {
+                let at = format!("conflicts[{}].sides[{}]", i, j);
+                if side.callchains.is_empty() {
+                    return Err(format!("{}.callchains is empty", at));
+                }
+                for (k, r) in side.callchains.iter().enumerate() {
+                    validate_range(r, &format!("{}.callchains[{}]", at, k))?;
+                }
+                validate_lock(&side.first, &format!("{}.first", at))?;
+                validate_lock(&side.second, &format!("{}.second", at))?;
+            }
+        }
+        for (i, area) in self.critical_sections.iter().enumerate() {
+            let first = match area.ranges.first() {
+                Some(first) => first,
+                None => return Err(format!("critical_sections[{}].ranges is empty", i)),
+            };
+            for (j, r) in area.ranges.iter().enumerate() {
+                let at = format!("critical_sections[{}].ranges[{}]", i, j);
+                validate_range(r, &at)?;
+                if r.file != first.file {
+                    return Err(format!("{}: {} is not in {}, the file of the area", at, r.file, first.file));
+                }
+            }
+            for (j, r) in area.triggers.iter().enumerate() {
+                validate_range(r, &format!("critical_sections[{}].triggers[{}]", i, j))?;
+            }
+            for (j, r) in area.await_points.iter().enumerate() {
+                let at = format!("critical_sections[{}].await_points[{}]", i, j);
+                validate_range(r, &at)?;
+                if r.file != first.file {
+                    return Err(format!("{}: {} is not in {}, the file of the area", at, r.file, first.file));
+                }
+            }
+        }
+        Ok(())
     }
 
     pub fn to_file(&self, output_path: &str) -> Result<(),Box<dyn Error>> {
@@ -57,10 +525,75 @@ impl AnalysisResult {
     }
 }
 
+fn validate_lock(lock: &LockInfo, at: &str) -> Result<(), String> {
+    if lock.expr.is_empty() {
+        return Err(format!("{}.expr is empty", at));
+    }
+    validate_range(&lock.acquired_at, &format!("{}.acquired_at", at))
+}
+
+fn validate_range(r: &RangeInFile, at: &str) -> Result<(), String> {
+    if r.file.is_empty() {
+        return Err(format!("{}: the file name is empty", at));
+    }
+    // Files become `file://` URIs, which only absolute paths have.
+    if !Path::new(&r.file).is_absolute() {
+        return Err(format!("{}: {} is not an absolute path", at, r.file));
+    }
+    if r.start.line == 0 || r.start.col == 0 || r.end.line == 0 || r.end.col == 0 {
+        return Err(format!("{}: {} has a line or column 0, they are 1 based", at, r));
+    }
+    if r.end < r.start {
+        return Err(format!("{}: {} ends before it starts", at, r));
+    }
+    Ok(())
+}
+
+/// One line of the result stream lockbud appends to while the build runs, so that
+/// findings show up crate by crate. Records of a crate may interleave with other crates'.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum StreamRecord {
+    /// The first line, with the `schema_version` the records follow.
+    Start { schema_version: u32 },
+    Call {
+        #[serde(rename = "crate")]
+        krate: String,
+        call: SuspiciousCall,
+    },
+    Conflict {
+        #[serde(rename = "crate")]
+        krate: String,
+        conflict: Box<LockConflict>,
+    },
+    CriticalSection {
+        #[serde(rename = "crate")]
+        krate: String,
+        critical_section: HighlightArea,
+    },
+    /// All records of the crate were written.
+    CrateDone {
+        #[serde(rename = "crate")]
+        krate: String,
+    },
+}
+
+/// The JSON Schema of the result files lockbud writes.
+pub fn json_schema() -> String {
+    serde_json::to_string_pretty(&schemars::schema_for!(AnalysisResult)).unwrap()
+}
+
+/// The JSON Schema of a line of the result stream.
+pub fn stream_json_schema() -> String {
+    serde_json::to_string_pretty(&schemars::schema_for!(StreamRecord)).unwrap()
+}
+
 #[cfg(test)]
 mod tests {
     use std::{fs};
 
+    use serde_json::json;
+
     use super::*;
 
     /// 
@@ -82,40 +615,40 @@ mod tests {
     
         let mut calls: Vec<SuspiciousCall> = Vec::new();
         calls.push(SuspiciousCall { callchains: vec![
-            ("/some/file1.rs".to_string(), 4, 5, 6, 7)
-        ], ty: Suspicious::DoubleLock });
+            RangeInFile::new("/some/file1.rs", 4, 5, 6, 7)
+        ], ty: Suspicious::DoubleLock, locks: Vec::new() });
         calls.push(SuspiciousCall { callchains: vec![
-            ("/some/file1.rs".to_string(), 6, 7, 8, 9)
-        ], ty: Suspicious::ConflictLock });
+            RangeInFile::new("/some/file1.rs", 6, 7, 8, 9)
+        ], ty: Suspicious::ConflictLock, locks: Vec::new() });
 
         calls.push(SuspiciousCall { callchains: vec![
-            ("/some/file2.rs".to_string(), 6, 7, 8, 9)
-        ], ty: Suspicious::ChRecv });
+            RangeInFile::new("/some/file2.rs", 6, 7, 8, 9)
+        ], ty: Suspicious::ChRecv, locks: Vec::new() });
 
         calls.push(SuspiciousCall { callchains: vec![
-            ("/some/file3.rs".to_string(), 6, 7, 8, 9)
-        ], ty: Suspicious::ChSend });
+            RangeInFile::new("/some/file3.rs", 6, 7, 8, 9)
+        ], ty: Suspicious::ChSend, locks: Vec::new() });
 
         calls.push(SuspiciousCall { callchains: vec![
-            ("/some/file4.rs".to_string(), 6, 7, 8, 9)
-        ], ty: Suspicious::CondVarWait });
+            RangeInFile::new("/some/file4.rs", 6, 7, 8, 9)
+        ], ty: Suspicious::CondVarWait, locks: Vec::new() });
 
-        let result = AnalysisResult {
+        let result = AnalysisResult::new(
             calls,
-            critical_sections: vec![
+            vec![
                 HighlightArea { triggers: vec![
-                    ("file1.rs".to_string(), 1, 2, 3, 4)
+                    RangeInFile::new("/some/file1.rs", 1, 2, 3, 4)
                 ], ranges: vec![
-                    ("file1.rs".to_string(), 5, 6, 7, 8)
-                ] },
+                    RangeInFile::new("/some/file1.rs", 5, 6, 7, 8)
+                ], await_points: Vec::new(), kind: SectionKind::Lock },
     
                 HighlightArea { triggers: vec![
-                    ("file2.rs".to_string(), 1, 2, 3, 4)
+                    RangeInFile::new("/some/file2.rs", 1, 2, 3, 4)
                 ], ranges: vec![
-                    ("file2.rs".to_string(), 5, 6, 7, 8)
-                ] }
+                    RangeInFile::new("/some/file2.rs", 5, 6, 7, 8)
+                ], await_points: Vec::new(), kind: SectionKind::Lock }
             ],
-        };
+        );
 
         result.to_file(tmp_result_file).unwrap();
 
@@ -128,4 +661,214 @@ mod tests {
         Ok(())
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_analysis_result_migrates_unversioned() -> Result<(),Box<dyn Error>> {
+        assert_eq!(MIGRATIONS.len(), SCHEMA_VERSION as usize);
+
+        let res = AnalysisResult::from_value(json!({
+            "calls": [{ "callchains": [["/a.rs", 1, 2, 1, 9]], "ty": "DoubleLock" }],
+            "critical_sections": []
+        }))?;
+        assert_eq!(res.schema_version, SCHEMA_VERSION);
+        assert_eq!(res.calls.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_analysis_result_reads_locks() -> Result<(),Box<dyn Error>> {
+        let res = AnalysisResult::from_value(json!({
+            "schema_version": 3,
+            "calls": [{
+                "callchains": [["/a.rs", 9, 5, 9, 20]],
+                "ty": "DoubleLock",
+                "locks": [{ "expr": "self.inner.state", "ty": "std::sync::Mutex<State>", "acquired_at": ["/a.rs", 7, 5, 7, 30] }]
+            }],
+            "critical_sections": []
+        }))?;
+        assert_eq!(res.calls[0].locks[0].describe(), "`self.inner.state` (std::sync::Mutex<State>)");
+
+        let err = AnalysisResult::from_value(json!({
+            "calls": [{
+                "callchains": [["/a.rs", 9, 5, 9, 20]],
+                "ty": "DoubleLock",
+                "locks": [{ "expr": "m", "ty": "std::sync::Mutex<()>", "acquired_at": ["/a.rs", 0, 5, 7, 30] }]
+            }],
+            "critical_sections": []
+        }));
+        assert!(err.unwrap_err().to_string().starts_with("calls[0].locks[0].acquired_at: "));
+        Ok(())
+    }
+
+    #[test]
+    fn test_analysis_result_reads_conflicts() -> Result<(),Box<dyn Error>> {
+        let lock = |expr: &str, line: u32| json!({ "expr": expr, "ty": "std::sync::Mutex<()>", "acquired_at": ["/a.rs", line, 9, line, 20] });
+        let mut value = json!({
+            "schema_version": 4,
+            "calls": [],
+            "conflicts": [{ "sides": [
+                { "first": lock("self.a", 2), "second": lock("self.b", 3), "callchains": [["/a.rs", 3, 9, 3, 20]] },
+                { "first": lock("self.b", 7), "second": lock("self.a", 8), "callchains": [["/a.rs", 8, 9, 8, 20]] }
+            ] }],
+            "critical_sections": []
+        });
+        let res = AnalysisResult::from_value(value.clone())?;
+        assert_eq!(res.conflicts[0].sides[1].second.expr, "self.a");
+
+        value["conflicts"][0]["sides"][1]["callchains"] = json!([]);
+        let err = AnalysisResult::from_value(value);
+        assert_eq!(err.unwrap_err().to_string(), "conflicts[0].sides[1].callchains is empty");
+        Ok(())
+    }
+
+    #[test]
+    fn test_suspicious_keeps_unknown_kinds() -> Result<(),Box<dyn Error>> {
+        for kind in Suspicious::KNOWN {
+            assert_eq!(&Suspicious::from_name(kind.name()), kind);
+        }
+
+        let res = AnalysisResult::from_value(json!({
+            "calls": [
+                { "callchains": [["/a.rs", 1, 2, 1, 9]], "ty": "BarrierWait" },
+                { "callchains": [["/a.rs", 3, 2, 3, 9]], "ty": "SemaphoreAcquire" }
+            ],
+            "critical_sections": []
+        }))?;
+        assert_eq!(res.calls[0].ty, Suspicious::BarrierWait);
+        assert_eq!(res.calls[1].ty, Suspicious::Unknown("SemaphoreAcquire".to_string()));
+        assert_eq!(serde_json::to_value(&res.calls[1].ty)?, json!("SemaphoreAcquire"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_analysis_result_reads_await_points() -> Result<(),Box<dyn Error>> {
+        let mut value = json!({
+            "schema_version": 6,
+            "calls": [{ "callchains": [["/a.rs", 4, 9, 4, 30]], "ty": "GuardAcrossAwait" }],
+            "critical_sections": [{
+                "triggers": [["/a.rs", 2, 9, 2, 20]],
+                "ranges": [["/a.rs", 2, 9, 6, 2]],
+                "await_points": [["/a.rs", 4, 24, 4, 30]]
+            }]
+        });
+        let res = AnalysisResult::from_value(value.clone())?;
+        assert_eq!(res.calls[0].ty, Suspicious::GuardAcrossAwait);
+        assert_eq!(res.critical_sections[0].await_points, vec![RangeInFile::new("/a.rs", 4, 24, 4, 30)]);
+
+        value["critical_sections"][0]["await_points"] = json!([["/b.rs", 4, 24, 4, 30]]);
+        let err = AnalysisResult::from_value(value);
+        assert_eq!(err.unwrap_err().to_string(), "critical_sections[0].await_points[0]: /b.rs is not in /a.rs, the file of the area");
+        Ok(())
+    }
+
+    #[test]
+    fn test_analysis_result_reads_section_kinds() -> Result<(),Box<dyn Error>> {
+        let res = AnalysisResult::from_value(json!({
+            "calls": [{ "callchains": [["/a.rs", 9, 5, 9, 20]], "ty": "IsrConflictLock" }],
+            "critical_sections": [
+                { "triggers": [["/a.rs", 2, 5, 2, 30]], "ranges": [["/a.rs", 2, 5, 4, 7]], "kind": "interrupt_free" },
+                { "triggers": [["/a.rs", 6, 5, 6, 30]], "ranges": [["/a.rs", 6, 5, 8, 7]], "kind": "critical_section" },
+                { "triggers": [["/a.rs", 9, 5, 9, 20]], "ranges": [["/a.rs", 9, 5, 9, 40]] }
+            ]
+        }))?;
+        assert_eq!(res.calls[0].ty, Suspicious::IsrConflictLock);
+        let kinds: Vec<SectionKind> = res.critical_sections.iter().map(|a| a.kind).collect();
+        assert_eq!(kinds, vec![SectionKind::InterruptFree, SectionKind::CriticalSection, SectionKind::Lock]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_analysis_result_rejects_unsupported_input() {
+        let err = AnalysisResult::from_value(json!({ "schema_version": 99, "calls": [], "critical_sections": [] }));
+        assert!(err.unwrap_err().to_string().contains("schema version 99 is newer"));
+
+        let err = AnalysisResult::from_value(json!([]));
+        assert_eq!(err.unwrap_err().to_string(), "the analysis result is not a JSON object");
+    }
+
+    #[test]
+    fn test_analysis_result_validate() {
+        let invalid = |calls: Value, critical_sections: Value| {
+            AnalysisResult::from_value(json!({ "calls": calls, "critical_sections": critical_sections }))
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            invalid(json!([{ "callchains": [], "ty": "ChSend" }]), json!([])),
+            "calls[0].callchains is empty"
+        );
+        assert_eq!(
+            invalid(json!([{ "callchains": [["/a.rs", 1, 1, 1, 2], ["/a.rs", 0, 1, 1, 2]], "ty": "ChSend" }]), json!([])),
+            "calls[0].callchains[1]: /a.rs:0:1-1:2 has a line or column 0, they are 1 based"
+        );
+        assert_eq!(
+            invalid(json!([]), json!([{ "triggers": [], "ranges": [] }])),
+            "critical_sections[0].ranges is empty"
+        );
+        assert_eq!(
+            invalid(json!([]), json!([{ "triggers": [], "ranges": [["/a.rs", 5, 1, 3, 1]] }])),
+            "critical_sections[0].ranges[0]: /a.rs:5:1-3:1 ends before it starts"
+        );
+        assert_eq!(
+            invalid(json!([]), json!([{ "triggers": [], "ranges": [["/a.rs", 1, 1, 3, 1], ["/b.rs", 1, 1, 3, 1]] }])),
+            "critical_sections[0].ranges[1]: /b.rs is not in /a.rs, the file of the area"
+        );
+        assert_eq!(
+            invalid(json!([{ "callchains": [["src/a.rs", 1, 1, 1, 2], ["/a.rs", 1, 1, 1, 2]], "ty": "ChSend" }]), json!([])),
+            "calls[0].callchains[0]: src/a.rs is not an absolute path"
+        );
+    }
+
+    #[test]
+    fn test_metadata_round_trip() -> Result<(),Box<dyn Error>> {
+        let mut result = AnalysisResult::new(Vec::new(), Vec::new());
+        result.metadata = Some(Metadata {
+            commit: Some("1a2b3c4-dirty".to_string()),
+            toolchain: Some("rustc 1.63.0-nightly (1f34da9ec 2022-06-14)".to_string()),
+            lockbud_version: None,
+            cargo_args: vec!["build".to_string(), "--message-format=json".to_string()],
+            crates: vec!["foo".to_string(), "bar".to_string()],
+            finished_at: 1_655_200_000,
+            duration_ms: 12_345,
+        });
+
+        fs::create_dir_all(".tmp")?;
+        result.to_file(".tmp/_test_result_metadata.json")?;
+        assert_eq!(AnalysisResult::from_file(".tmp/_test_result_metadata.json")?, result);
+        assert_eq!(
+            result.metadata.unwrap().summary(),
+            "commit 1a2b3c4-dirty, rustc 1.63.0-nightly (1f34da9ec 2022-06-14), crates foo, bar, took 12.3 s"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_schema_is_up_to_date() -> Result<(),Box<dyn Error>> {
+        // Regenerate with `deadlock-lsp schema > schema/analysis_result.schema.json`.
+        let checked_in: Value = serde_json::from_str(&fs::read_to_string("schema/analysis_result.schema.json")?)?;
+        let generated: Value = serde_json::from_str(&json_schema())?;
+        assert_eq!(checked_in, generated);
+
+        // Regenerate with `deadlock-lsp schema stream > schema/stream_record.schema.json`.
+        let checked_in: Value = serde_json::from_str(&fs::read_to_string("schema/stream_record.schema.json")?)?;
+        let generated: Value = serde_json::from_str(&stream_json_schema())?;
+        assert_eq!(checked_in, generated);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_record_format() -> Result<(),Box<dyn Error>> {
+        let record = StreamRecord::Call {
+            krate: "foo".to_string(),
+            call: SuspiciousCall { callchains: vec![RangeInFile::new("/a.rs", 1, 2, 1, 9)], ty: Suspicious::ChRecv, locks: Vec::new() },
+        };
+        assert_eq!(
+            serde_json::to_value(&record)?,
+            json!({ "record": "call", "crate": "foo", "call": { "callchains": [["/a.rs", 1, 2, 1, 9]], "ty": "ChRecv", "locks": [] } })
+        );
+        let done: StreamRecord = serde_json::from_str(r#"{"record":"crate_done","crate":"foo"}"#)?;
+        assert_eq!(done, StreamRecord::CrateDone { krate: "foo".to_string() });
+        Ok(())
+    }
+
+}
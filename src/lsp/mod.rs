@@ -1,15 +1,23 @@
 use lsp_server::{RequestId, Request, ExtractError, Notification};
-use lsp_types::{ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncOptions, SelectionRangeProviderCapability, OneOf, SaveOptions};
+use lsp_types::{ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncOptions, SelectionRangeProviderCapability, OneOf, SaveOptions, HoverProviderCapability, CodeLensOptions, ExecuteCommandOptions, CallHierarchyServerCapability, CodeActionProviderCapability, CodeActionOptions, CodeActionKind, WorkspaceServerCapabilities, WorkspaceFoldersServerCapabilities};
 use serde_json::Value;
 
+use self::{code_lens::REANALYZE_COMMAND, line_index::PositionEncoding};
 
+
+pub mod call_hierarchy;
+pub mod code_action;
+pub mod code_lens;
+pub mod ext;
 pub mod global_ctxt;
+pub mod hover;
+pub mod line_index;
 pub mod lockbud_ty;
 
 
-pub fn get_capabilities() -> Value {
+pub fn get_capabilities(position_encoding: PositionEncoding) -> Value {
     // Run the server and wait for the two threads to end (typically by trigger LSP Exit event).
-    serde_json::to_value(
+    let mut capabilities = serde_json::to_value(
         &ServerCapabilities {
             text_document_sync: Some(TextDocumentSyncCapability::Options(TextDocumentSyncOptions {
                 will_save: None,
@@ -20,10 +28,32 @@ pub fn get_capabilities() -> Value {
             })),
             selection_range_provider: Some(SelectionRangeProviderCapability::Simple(true)),
             document_highlight_provider: Some(OneOf::Left(true)),
-            
+            hover_provider: Some(HoverProviderCapability::Simple(true)),
+            call_hierarchy_provider: Some(CallHierarchyServerCapability::Simple(true)),
+            code_action_provider: Some(CodeActionProviderCapability::Options(CodeActionOptions {
+                code_action_kinds: Some(vec![CodeActionKind::QUICKFIX]),
+                work_done_progress_options: Default::default(),
+                resolve_provider: None,
+            })),
+            code_lens_provider: Some(CodeLensOptions { resolve_provider: Some(false) }),
+            execute_command_provider: Some(ExecuteCommandOptions {
+                commands: vec![REANALYZE_COMMAND.to_string()],
+                work_done_progress_options: Default::default(),
+            }),
+            workspace: Some(WorkspaceServerCapabilities {
+                workspace_folders: Some(WorkspaceFoldersServerCapabilities {
+                    supported: Some(true),
+                    change_notifications: Some(OneOf::Left(true)),
+                }),
+                file_operations: None,
+            }),
+
             ..Default::default()
         }
-    ).unwrap()
+    ).unwrap();
+    // LSP 3.17, set by hand so that it does not depend on the lsp-types version.
+    capabilities["positionEncoding"] = position_encoding.name().into();
+    capabilities
 }
 
 pub fn cast_request<R>(req: Request) -> Result<(RequestId, R::Params), ExtractError<Request>>
@@ -52,8 +82,14 @@ mod tests {
 
     #[test]
     fn test_get_capabilities() {
-        let res = get_capabilities();
+        let res = get_capabilities(PositionEncoding::Utf8);
         assert!(res.get("documentHighlightProvider").is_some());
+        assert_eq!(res["hoverProvider"], true);
+        assert_eq!(res["callHierarchyProvider"], true);
+        assert_eq!(res["codeActionProvider"]["codeActionKinds"][0], "quickfix");
+        assert_eq!(res["executeCommandProvider"]["commands"][0], "rust-deadlock-detector.reanalyze");
+        assert_eq!(res["positionEncoding"], "utf-8");
+        assert_eq!(res["workspace"]["workspaceFolders"]["changeNotifications"], true);
 
     }
 
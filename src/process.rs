@@ -0,0 +1,188 @@
+//! Process tree handling for the analysis build, so that killing cargo also
+//! stops the rustc and lockbud processes it spawned.
+
+use std::process::{Child, Command};
+
+/// Makes the spawned process the leader of a new process group, which its
+/// children inherit.
+pub fn new_process_group(cmd: &mut Command) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // SAFETY: setpgid is async-signal-safe and touches no memory of the parent.
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setpgid(0, 0) == 0 {
+                    Ok(())
+                } else {
+                    Err(std::io::Error::last_os_error())
+                }
+            });
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = cmd;
+}
+
+/// Kills the process group led by `child`, or only `child` if it does not lead one,
+/// and reaps it.
+pub fn kill_tree(child: &mut Child) {
+    #[cfg(unix)]
+    let killed = unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } == 0;
+    #[cfg(not(unix))]
+    let killed = false;
+
+    if !killed {
+        if let Err(err) = child.kill() {
+            eprintln!("kill {} error: {}", child.id(), err);
+        }
+    }
+    let _ = child.wait();
+}
+
+/// Whether `child` exited. Where possible it is left unreaped, so that its pid, and
+/// with it the id of the process group it leads, cannot be reused yet.
+pub fn has_exited(child: &mut Child) -> std::io::Result<bool> {
+    #[cfg(unix)]
+    {
+        // SAFETY: waitid only writes to `info`, zeroed as it is left untouched without a state change.
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+        if unsafe { libc::waitid(libc::P_PID, child.id() as libc::id_t, &mut info, flags) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(info.si_signo != 0)
+    }
+    #[cfg(not(unix))]
+    child.try_wait().map(|status| status.is_some())
+}
+
+/// Kills what is left of the process group led by the exited `child`, e.g. a process
+/// that still holds the output pipes of the build, then reaps `child`.
+pub fn kill_group_of_exited(child: &mut Child) -> std::io::Result<std::process::ExitStatus> {
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    child.wait()
+}
+
+/// Resident memory of all processes in the process group `pgid`, in KiB.
+/// Only available where `/proc` is.
+pub fn group_memory_kb(pgid: u32) -> Option<u64> {
+    let entries = std::fs::read_dir("/proc").ok()?;
+    let mut total = 0;
+    for entry in entries.flatten() {
+        let pid = entry.file_name();
+        let pid = pid.to_string_lossy();
+        if !pid.bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        let stat = match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+        if stat_pgrp(&stat) != Some(pgid) {
+            continue;
+        }
+        if let Ok(status) = std::fs::read_to_string(format!("/proc/{}/status", pid)) {
+            total += status_rss_kb(&status).unwrap_or(0);
+        }
+    }
+    Some(total)
+}
+
+/// The process group in `/proc/<pid>/stat`. The command name may contain
+/// spaces and parentheses, so fields are counted after its closing parenthesis.
+fn stat_pgrp(stat: &str) -> Option<u32> {
+    let rest = &stat[stat.rfind(')')? + 1..];
+    // state, ppid, pgrp
+    rest.split_whitespace().nth(2)?.parse().ok()
+}
+
+fn status_rss_kb(status: &str) -> Option<u64> {
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn test_stat_pgrp() {
+        assert_eq!(stat_pgrp("1234 (cargo) S 1 1234 1234 0 -1"), Some(1234));
+        assert_eq!(stat_pgrp("1234 (my (weird) name) R 7 99 99 0 -1"), Some(99));
+        assert_eq!(stat_pgrp("garbage"), None);
+    }
+
+    #[test]
+    fn test_status_rss_kb() {
+        assert_eq!(status_rss_kb("Name:\tcargo\nVmRSS:\t   20480 kB\nThreads:\t4\n"), Some(20480));
+        assert_eq!(status_rss_kb("Name:\tkthreadd\n"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_has_exited_leaves_the_group_to_kill() {
+        use std::{io::Read, process::Stdio};
+
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("sleep 30 & exit 3");
+        cmd.stdout(Stdio::piped());
+        new_process_group(&mut cmd);
+        let mut child = cmd.spawn().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+
+        let start = Instant::now();
+        while !has_exited(&mut child).unwrap() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // Still unreaped, so the exit is reported again.
+        assert!(has_exited(&mut child).unwrap());
+        assert_eq!(kill_group_of_exited(&mut child).unwrap().code(), Some(3));
+
+        // The background sleep was killed with the group and closed the pipe.
+        let mut out = String::new();
+        stdout.read_to_string(&mut out).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_kill_tree_kills_grandchildren() {
+        use std::{io::Read, process::Stdio};
+
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("sleep 30 & wait");
+        cmd.stdout(Stdio::piped());
+        new_process_group(&mut cmd);
+        let mut child = cmd.spawn().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+
+        std::thread::sleep(Duration::from_millis(200));
+        let start = Instant::now();
+        kill_tree(&mut child);
+        // The pipe only closes once the grandchild `sleep` is gone as well.
+        let mut out = Vec::new();
+        stdout.read_to_end(&mut out).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_group_memory_kb() {
+        let mut cmd = Command::new("sleep");
+        cmd.arg("30");
+        new_process_group(&mut cmd);
+        let mut child = cmd.spawn().unwrap();
+
+        std::thread::sleep(Duration::from_millis(200));
+        let memory = group_memory_kb(child.id());
+        kill_tree(&mut child);
+        assert!(memory.unwrap() > 0);
+    }
+}
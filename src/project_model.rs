@@ -0,0 +1,234 @@
+use std::{error::Error, path::{Path, PathBuf}, process::Command};
+
+use serde::Deserialize;
+
+use crate::utils::cargo;
+
+/// The subset of `cargo metadata --format-version 1` output the analysis needs.
+#[derive(Debug, Deserialize)]
+struct Metadata {
+    packages: Vec<Package>,
+    workspace_members: Vec<String>,
+    workspace_root: PathBuf,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct Package {
+    pub id: String,
+    pub name: String,
+    pub manifest_path: PathBuf,
+    pub targets: Vec<Target>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub name: String,
+    pub kind: Vec<String>,
+    pub src_path: PathBuf,
+}
+
+/// Workspace members of a cargo project, as reported by `cargo metadata`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProjectModel {
+    pub workspace_root: PathBuf,
+    pub members: Vec<Package>,
+}
+
+impl Package {
+    /// The directory holding the package's `Cargo.toml`.
+    pub fn root(&self) -> &Path {
+        self.manifest_path.parent().unwrap_or(&self.manifest_path)
+    }
+
+    /// Crate names as rustc sees them, one per target.
+    pub fn crate_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.targets.iter().map(Target::crate_name).collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+impl Target {
+    /// Cargo target names may contain dashes, rustc crate names never do.
+    pub fn crate_name(&self) -> String {
+        self.name.replace('-', "_")
+    }
+}
+
+impl ProjectModel {
+    pub fn load(dir: &Path) -> Result<ProjectModel, Box<dyn Error>> {
+        let mut cmd = cargo();
+        cmd.args(["metadata", "--format-version", "1", "--no-deps"]);
+        cmd.current_dir(dir);
+        let output = cmd.output()?;
+        if !output.status.success() {
+            return Err(format!(
+                "{:?} failed: {}",
+                cmd,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        Self::from_json(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    pub fn from_json(json: &str) -> Result<ProjectModel, Box<dyn Error>> {
+        let metadata: Metadata = serde_json::from_str(json)?;
+        let members = metadata
+            .packages
+            .into_iter()
+            .filter(|p| metadata.workspace_members.contains(&p.id))
+            .collect();
+        Ok(ProjectModel {
+            workspace_root: metadata.workspace_root,
+            members,
+        })
+    }
+
+    /// Members to analyze for `dir`: every member for the workspace root,
+    /// otherwise the members located under `dir`.
+    pub fn packages_in(&self, dir: &Path) -> Vec<&Package> {
+        if dir == self.workspace_root {
+            return self.members.iter().collect();
+        }
+        self.members.iter().filter(|p| p.root().starts_with(dir)).collect()
+    }
+
+    pub fn crate_names(packages: &[&Package]) -> Vec<String> {
+        let mut names: Vec<String> = packages.iter().flat_map(|p| p.crate_names()).collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Directories holding the sources of the given packages' targets.
+    pub fn source_roots(packages: &[&Package]) -> Vec<PathBuf> {
+        let mut roots: Vec<PathBuf> = packages
+            .iter()
+            .flat_map(|p| p.targets.iter())
+            .filter_map(|t| t.src_path.parent().map(Path::to_path_buf))
+            .collect();
+        roots.sort();
+        roots.dedup();
+        // Sorting puts parents before their subdirectories.
+        let mut result: Vec<PathBuf> = Vec::new();
+        for root in roots {
+            if !result.iter().any(|r| root.starts_with(r)) {
+                result.push(root);
+            }
+        }
+        result
+    }
+
+    /// Adds `-p <name>` for each package to a cargo command.
+    pub fn package_args(cmd: &mut Command, packages: &[&Package]) {
+        for p in packages {
+            cmd.arg("-p");
+            cmd.arg(&p.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    const METADATA: &str = r#"{
+        "packages": [
+            {
+                "id": "app 0.1.0 (path+file:///ws/app)",
+                "name": "my-app",
+                "manifest_path": "/ws/app/Cargo.toml",
+                "targets": [
+                    { "name": "my-app", "kind": ["bin"], "src_path": "/ws/app/src/main.rs" },
+                    { "name": "helper", "kind": ["bin"], "src_path": "/ws/app/src/bin/helper.rs" }
+                ]
+            },
+            {
+                "id": "core 0.1.0 (path+file:///ws/core)",
+                "name": "my-core",
+                "manifest_path": "/ws/core/Cargo.toml",
+                "targets": [
+                    { "name": "my_core", "kind": ["lib"], "src_path": "/ws/core/src/lib.rs" },
+                    { "name": "smoke", "kind": ["test"], "src_path": "/ws/core/tests/smoke.rs" }
+                ]
+            }
+        ],
+        "workspace_members": [
+            "app 0.1.0 (path+file:///ws/app)",
+            "core 0.1.0 (path+file:///ws/core)"
+        ],
+        "workspace_root": "/ws",
+        "target_directory": "/ws/target",
+        "version": 1
+    }"#;
+
+    #[test]
+    fn test_project_model_from_json() {
+        let model = ProjectModel::from_json(METADATA).unwrap();
+        assert_eq!(model.workspace_root, PathBuf::from("/ws"));
+        assert_eq!(model.members.len(), 2);
+        assert_eq!(model.members[0].root(), Path::new("/ws/app"));
+    }
+
+    #[test]
+    fn test_project_model_packages_in() {
+        let model = ProjectModel::from_json(METADATA).unwrap();
+        assert_eq!(model.packages_in(Path::new("/ws")).len(), 2);
+
+        let core = model.packages_in(Path::new("/ws/core"));
+        assert_eq!(core.len(), 1);
+        assert_eq!(core[0].name, "my-core");
+        assert!(model.packages_in(Path::new("/elsewhere")).is_empty());
+    }
+
+    #[test]
+    fn test_project_model_crate_names_use_underscores() {
+        let model = ProjectModel::from_json(METADATA).unwrap();
+        let packages = model.packages_in(Path::new("/ws"));
+        assert_eq!(
+            ProjectModel::crate_names(&packages),
+            vec!["helper", "my_app", "my_core", "smoke"]
+        );
+    }
+
+    #[test]
+    fn test_project_model_source_roots() {
+        let model = ProjectModel::from_json(METADATA).unwrap();
+        let packages = model.packages_in(Path::new("/ws"));
+        assert_eq!(
+            ProjectModel::source_roots(&packages),
+            vec![
+                PathBuf::from("/ws/app/src"),
+                PathBuf::from("/ws/core/src"),
+                PathBuf::from("/ws/core/tests"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_project_model_load_virtual_manifest() -> Result<(), Box<dyn Error>> {
+        let ws = fs::canonicalize(".")?.join(".tmp/fake_virtual_ws");
+        fs::create_dir_all(ws.join("member/src"))?;
+        fs::write(ws.join("Cargo.toml"), "[workspace]\nmembers = [\"member\"]\n")?;
+        fs::write(
+            ws.join("member/Cargo.toml"),
+            "[package]\nname = \"fake-member\"\nversion = \"0.1.0\"\n",
+        )?;
+        fs::write(ws.join("member/src/lib.rs"), "")?;
+
+        let model = ProjectModel::load(&ws)?;
+        assert_eq!(model.workspace_root, ws);
+        assert_eq!(model.members.len(), 1);
+        assert_eq!(model.members[0].crate_names(), vec!["fake_member"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_project_model_load_should_fail() {
+        assert!(ProjectModel::load(Path::new("/")).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    env,
+    path::Path,
+    process::Command,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::lsp::lockbud_ty::Metadata;
+
+/// Describes the run of `cmd`, the analysis `cargo build`, in `workspace`.
+/// Called before the build starts, so the commit is the one that was analyzed.
+pub fn collect(workspace: &Path, cmd: &Command) -> Metadata {
+    Metadata {
+        commit: git_commit(workspace),
+        toolchain: first_line(Command::new("rustc").arg("-V").current_dir(workspace)),
+        lockbud_version: env::var_os("__DL_RUSTC")
+            .filter(|p| !p.is_empty())
+            .and_then(|lockbud| first_line(Command::new(lockbud).arg("--version"))),
+        cargo_args: cmd.get_args().map(|a| a.to_string_lossy().into_owned()).collect(),
+        crates: crate_names(cmd),
+        finished_at: 0,
+        duration_ms: 0,
+    }
+}
+
+/// Stamps the end of a successful run.
+pub fn finish(metadata: &mut Metadata, elapsed: Duration) {
+    metadata.finished_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    metadata.duration_ms = elapsed.as_millis() as u64;
+}
+
+fn first_line(cmd: &mut Command) -> Option<String> {
+    let output = cmd.output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout.lines().map(str::trim).find(|l| !l.is_empty()).map(str::to_string)
+}
+
+fn git_commit(workspace: &Path) -> Option<String> {
+    let commit = first_line(Command::new("git").args(["rev-parse", "HEAD"]).current_dir(workspace))?;
+    // Untracked files include the server's own `.rda` output, which would make every run dirty.
+    let status = Command::new("git")
+        .args(["status", "--porcelain", "--untracked-files=no"])
+        .current_dir(workspace)
+        .output()
+        .ok()?;
+    if status.stdout.is_empty() {
+        Some(commit)
+    } else {
+        Some(format!("{}-dirty", commit))
+    }
+}
+
+/// The crates lockbud was told to analyze through `__DL_CRATE`.
+fn crate_names(cmd: &Command) -> Vec<String> {
+    let crates = cmd.get_envs().find(|(key, _)| *key == "__DL_CRATE").and_then(|(_, value)| value);
+    match crates {
+        Some(crates) => crates
+            .to_string_lossy()
+            .split(',')
+            .filter(|c| !c.is_empty())
+            .map(str::to_string)
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+
+    #[test]
+    fn test_collect_describes_cmd() {
+        let mut cmd = Command::new("cargo");
+        cmd.args(["build", "-p", "foo"]);
+        cmd.env("__DL_CRATE", "foo,foo_cli");
+
+        let metadata = collect(Path::new("."), &cmd);
+        assert_eq!(metadata.cargo_args, vec!["build", "-p", "foo"]);
+        assert_eq!(metadata.crates, vec!["foo", "foo_cli"]);
+        assert!(metadata.toolchain.unwrap().starts_with("rustc "));
+    }
+
+    #[test]
+    fn test_git_commit() -> Result<(), Box<dyn Error>> {
+        let repo = std::fs::canonicalize(".")?.join(".tmp/fake_git_repo");
+        let _ = std::fs::remove_dir_all(&repo);
+        std::fs::create_dir_all(&repo)?;
+        let git = |args: &[&str]| Command::new("git").args(args).current_dir(&repo).output();
+        git(&["init", "-q"])?;
+        std::fs::write(repo.join("lib.rs"), "")?;
+        git(&["add", "."])?;
+        git(&["-c", "user.name=t", "-c", "user.email=t@t", "commit", "-qm", "init"])?;
+
+        let commit = git_commit(&repo).unwrap();
+        assert_eq!(commit.len(), 40);
+
+        // The analysis output of an earlier run is not a change.
+        std::fs::create_dir_all(repo.join(".rda"))?;
+        std::fs::write(repo.join(".rda/a.json"), "{}")?;
+        assert_eq!(git_commit(&repo), Some(commit.clone()));
+
+        std::fs::write(repo.join("lib.rs"), "fn f() {}")?;
+        assert_eq!(git_commit(&repo), Some(format!("{}-dirty", commit)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_finish() {
+        let mut metadata = Metadata::default();
+        finish(&mut metadata, Duration::from_millis(1500));
+        assert_eq!(metadata.duration_ms, 1500);
+        assert!(metadata.finished_at > 0);
+    }
+}
@@ -0,0 +1,264 @@
+//! Reading of the result stream lockbud appends a `StreamRecord` per line to while
+//! the build runs. lockbud versions without streaming only write the result file.
+
+use std::{
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use serde_json::{json, Value};
+
+use crate::lsp::lockbud_ty::AnalysisResult;
+
+/// Where lockbud streams the records of the result file at `out`, passed as `__DL_STREAM`.
+pub fn stream_path(out: &Path) -> PathBuf {
+    out.with_extension("ndjson")
+}
+
+/// Follows the stream from the start, keeping every record read so far.
+pub struct ResultStream {
+    path: PathBuf,
+    /// Bytes of the file already read.
+    offset: u64,
+    /// An incomplete last line, finished by the next read.
+    partial: Vec<u8>,
+    exists: bool,
+    schema_version: Option<Value>,
+    /// The finding records read so far, with their `crate`.
+    records: Vec<Value>,
+    /// The records of the crates the last run finished, standing in for their crates
+    /// until this run finishes them again.
+    previous: Vec<Value>,
+    /// Crates whose records are complete, in the order they finished.
+    pub done: Vec<String>,
+}
+
+impl ResultStream {
+    pub fn new(path: PathBuf) -> Self {
+        ResultStream {
+            path,
+            offset: 0,
+            partial: Vec::new(),
+            exists: false,
+            schema_version: None,
+            records: Vec::new(),
+            previous: Vec::new(),
+            done: Vec::new(),
+        }
+    }
+
+    /// Starts following a new stream at `path`, keeping the complete crates of the
+    /// stream the last run left there.
+    pub fn restart(path: PathBuf) -> Self {
+        let mut last = ResultStream::new(path.clone());
+        last.finish();
+        if let Err(err) = fs::remove_file(&path) {
+            if last.exists {
+                eprintln!("remove {:?}: {}", path, err);
+            }
+        }
+        let mut stream = ResultStream::new(path);
+        stream.previous = last.records.into_iter().filter(|record| last.done.contains(&crate_of(record))).collect();
+        stream
+    }
+
+    /// Whether lockbud wrote a stream at all.
+    pub fn exists(&self) -> bool {
+        self.exists
+    }
+
+    /// Reads the lines appended since the last call and returns the crates they finished.
+    pub fn poll(&mut self) -> Vec<String> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(_) => return Vec::new(),
+        };
+        self.exists = true;
+        let mut appended = Vec::new();
+        let read = file.seek(SeekFrom::Start(self.offset)).and_then(|_| file.read_to_end(&mut appended));
+        match read {
+            Ok(n) => self.offset += n as u64,
+            Err(err) => {
+                eprintln!("read {:?}: {}", self.path, err);
+                return Vec::new();
+            },
+        }
+        self.partial.extend(appended);
+
+        let mut finished = Vec::new();
+        while let Some(end) = self.partial.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            finished.extend(self.push_line(&String::from_utf8_lossy(&line)));
+        }
+        finished
+    }
+
+    /// Reads the rest of the stream once lockbud exited, including a last line without newline.
+    pub fn finish(&mut self) -> Vec<String> {
+        let mut finished = self.poll();
+        let line = std::mem::take(&mut self.partial);
+        finished.extend(self.push_line(&String::from_utf8_lossy(&line)));
+        finished
+    }
+
+    /// Keeps the record on `line`, returns the crate it finished if it is a `crate_done`.
+    fn push_line(&mut self, line: &str) -> Option<String> {
+        let line = line.trim();
+        if line.is_empty() {
+            return None;
+        }
+        let mut record: Value = match serde_json::from_str(line) {
+            Ok(record) => record,
+            Err(err) => {
+                eprintln!("parse stream record {:?}: {}", line, err);
+                return None;
+            },
+        };
+        match record["record"].as_str() {
+            Some("start") => self.schema_version = Some(record["schema_version"].take()),
+            Some("call") | Some("conflict") | Some("critical_section") => self.records.push(record),
+            Some("crate_done") => {
+                let krate = crate_of(&record);
+                self.done.push(krate.clone());
+                return Some(krate);
+            },
+            // Records of a newer lockbud.
+            _ => eprintln!("unknown stream record {:?}", line),
+        }
+        None
+    }
+
+    /// The records read so far as one result, migrated and validated like a result file.
+    pub fn result(&self) -> Result<AnalysisResult, String> {
+        self.collect(self.records.iter())
+    }
+
+    /// The findings while the build runs: those of the crates finished in this run, and
+    /// those the last run found in the other crates.
+    pub fn partial_result(&self) -> Result<AnalysisResult, String> {
+        let finished = |record: &&Value| self.done.contains(&crate_of(record));
+        let previous = self.previous.iter().filter(|record| !finished(record));
+        self.collect(self.records.iter().filter(finished).chain(previous))
+    }
+
+    fn collect<'a>(&self, records: impl Iterator<Item = &'a Value>) -> Result<AnalysisResult, String> {
+        let mut value = json!({ "calls": [], "conflicts": [], "critical_sections": [] });
+        for record in records {
+            if let Some(kind) = record["record"].as_str() {
+                if let Some(list) = value[kind_list(kind)].as_array_mut() {
+                    list.push(record[kind].clone());
+                }
+            }
+        }
+        if let Some(version) = &self.schema_version {
+            value["schema_version"] = version.clone();
+        }
+        AnalysisResult::from_value(value).map_err(|err| format!("read {:?}: {}", self.path, err))
+    }
+}
+
+fn crate_of(record: &Value) -> String {
+    record["crate"].as_str().unwrap_or("unknown").to_string()
+}
+
+/// The result field holding the findings of a record kind.
+fn kind_list(kind: &str) -> &'static str {
+    match kind {
+        "call" => "calls",
+        "conflict" => "conflicts",
+        _ => "critical_sections",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, fs, io::Write};
+
+    use crate::lsp::lockbud_ty::{RangeInFile, StreamRecord, Suspicious, SuspiciousCall, SCHEMA_VERSION};
+
+    use super::*;
+
+    fn line(record: &StreamRecord) -> String {
+        format!("{}\n", serde_json::to_string(record).unwrap())
+    }
+
+    fn call(krate: &str, line: u32) -> StreamRecord {
+        StreamRecord::Call {
+            krate: krate.to_string(),
+            call: SuspiciousCall { callchains: vec![RangeInFile::new("/a.rs", line, 5, line, 9)], ty: Suspicious::DoubleLock, locks: Vec::new() },
+        }
+    }
+
+    #[test]
+    fn test_result_stream_reads_crate_by_crate() -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(".tmp")?;
+        let path = fs::canonicalize(".tmp")?.join("_test_stream.ndjson");
+        let _ = fs::remove_file(&path);
+        let mut stream = ResultStream::new(path.clone());
+        assert!(stream.poll().is_empty());
+        assert!(!stream.exists());
+
+        let mut file = File::create(&path)?;
+        file.write_all(line(&StreamRecord::Start { schema_version: SCHEMA_VERSION }).as_bytes())?;
+        file.write_all(line(&call("foo", 1)).as_bytes())?;
+        file.write_all(line(&call("bar", 2)).as_bytes())?;
+        file.write_all(line(&StreamRecord::CrateDone { krate: "foo".to_string() }).as_bytes())?;
+        // A record lockbud is still writing.
+        let next = line(&call("bar", 3));
+        file.write_all(&next.as_bytes()[..10])?;
+
+        assert_eq!(stream.poll(), vec!["foo"]);
+        assert_eq!(stream.result()?.calls.len(), 2);
+
+        file.write_all(&next.as_bytes()[10..])?;
+        file.write_all(line(&StreamRecord::CrateDone { krate: "bar".to_string() }).trim_end().as_bytes())?;
+        assert!(stream.poll().is_empty());
+        assert_eq!(stream.finish(), vec!["bar"]);
+        assert_eq!(stream.done, vec!["foo", "bar"]);
+        assert_eq!(stream.result()?.calls[2].callchains[0].start.line, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_result_stream_keeps_unfinished_crates_of_last_run() -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(".tmp")?;
+        let path = fs::canonicalize(".tmp")?.join("_test_stream_restart.ndjson");
+        let mut file = File::create(&path)?;
+        for record in [call("foo", 1), call("bar", 2), StreamRecord::CrateDone { krate: "foo".to_string() }] {
+            file.write_all(line(&record).as_bytes())?;
+        }
+        file.write_all(line(&StreamRecord::CrateDone { krate: "bar".to_string() }).as_bytes())?;
+        // Never finished by the last run.
+        file.write_all(line(&call("baz", 3)).as_bytes())?;
+
+        let mut stream = ResultStream::restart(path.clone());
+        assert!(!path.exists());
+        let lines = |result: AnalysisResult| -> Vec<u32> {
+            result.calls.iter().map(|call| call.callchains[0].start.line).collect()
+        };
+        assert_eq!(lines(stream.partial_result()?), vec![1, 2]);
+
+        let mut file = File::create(&path)?;
+        file.write_all(line(&call("foo", 4)).as_bytes())?;
+        file.write_all(line(&call("bar", 5)).as_bytes())?;
+        file.write_all(line(&StreamRecord::CrateDone { krate: "foo".to_string() }).as_bytes())?;
+        assert_eq!(stream.poll(), vec!["foo"]);
+        // foo is replaced, bar is still the last run's until it finishes.
+        assert_eq!(lines(stream.partial_result()?), vec![4, 2]);
+        assert_eq!(lines(stream.result()?), vec![4, 5]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_result_stream_validates_records() -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(".tmp")?;
+        let path = fs::canonicalize(".tmp")?.join("_test_stream_invalid.ndjson");
+        fs::write(&path, "not json\n{\"record\":\"call\",\"crate\":\"foo\",\"call\":{\"callchains\":[],\"ty\":\"ChSend\"}}\n")?;
+
+        let mut stream = ResultStream::new(path);
+        assert!(stream.finish().is_empty());
+        assert!(stream.result().unwrap_err().ends_with("calls[0].callchains is empty"));
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
-use std::{process::{Command, Stdio, ExitStatus}, fs, ffi::OsString, env};
+use std::{process::{Command, Stdio}, fs, ffi::OsString, env, path::{Path, PathBuf}};
 
-use serde::Deserialize;
+use crate::{config::CargoConfig, project_model::ProjectModel, result_stream::stream_path};
 
 /// Copied from Miri
 /// Returns the "default sysroot" if no `--sysroot` flag is set.
@@ -26,78 +26,162 @@ pub fn compile_time_sysroot() -> Option<String> {
 }
 
 
-#[derive(Debug, Deserialize)]
-struct Cargo {
-    package: CargoPackage,
+pub(crate) fn cargo() -> Command {
+    Command::new(env::var_os("CARGO").unwrap_or_else(|| OsString::from("cargo")))
 }
 
-#[derive(Debug, Deserialize)]
-struct CargoPackage {
-    name: String
-}
 
-fn cargo() -> Command {
-    Command::new(env::var_os("CARGO").unwrap_or_else(|| OsString::from("cargo")))
+/// Target directory used for analysis builds, so the user's regular `target/`
+/// is never touched and the dependency artifacts stay warm between runs.
+pub fn analysis_target_dir(ws_dir: &Path) -> PathBuf {
+    ws_dir.join(".rda/target")
 }
 
-
-pub fn get_analysis_cmd(dir: &str, out: &str) -> Command {
-    let ws_dir = std::path::Path::new(dir);
-    eprintln!("running analysis in directory: {}", dir);
-    let mut crate_name = None;
-    let cargo_toml_fp = ws_dir.join("Cargo.toml");
-    match fs::read_to_string(&cargo_toml_fp) {
-        Ok(toml_str) => {
-            let decoded: Cargo = toml::from_str(&toml_str).unwrap();
-            crate_name = Some(decoded.package.name)
-        },
+fn load_project_model(ws_dir: &Path) -> Option<ProjectModel> {
+    match ProjectModel::load(ws_dir) {
+        Ok(model) => Some(model),
         Err(err) => {
-            eprintln!("read {:?}: {}", cargo_toml_fp, err)
+            eprintln!("load project model at {:?}: {}", ws_dir, err);
+            None
         },
     }
+}
+
+/// Builds the packages of `model` that live under `ws_dir` with lockbud as
+/// rustc wrapper. `__DL_CRATE` holds the comma separated rustc crate names
+/// lockbud should instrument, `__DL_STREAM` where to stream findings to.
+fn analysis_cmd(ws_dir: &Path, out: &str, model: Option<&ProjectModel>, config: &CargoConfig) -> Command {
+    let packages = model.map(|m| m.packages_in(ws_dir)).unwrap_or_default();
 
     let mut cmd = cargo();
+    config.apply_env(&mut cmd);
 
     let dl_rustc = env::var_os("__DL_RUSTC").unwrap_or(OsString::from(""));
     cmd.env("RUSTC_WRAPPER", dl_rustc);
-    cmd.env("__DL_CRATE", crate_name.unwrap_or("".to_string()));
+    cmd.env("CARGO_TARGET_DIR", analysis_target_dir(ws_dir));
+    cmd.env("__DL_CRATE", ProjectModel::crate_names(&packages).join(","));
     cmd.env("__DL_OUT", out);
+    cmd.env("__DL_STREAM", stream_path(Path::new(out)));
     cmd.arg("build");
+    cmd.arg("--message-format=json");
+    ProjectModel::package_args(&mut cmd, &packages);
+    cmd.args(config.build_args());
     cmd.current_dir(ws_dir);
-    cmd.stdout(Stdio::null());
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
     eprintln!("{:?} in {:?}", cmd, ws_dir);
 
     cmd
 }
 
-pub fn run_analysis_in_dir(dir: &str, out: &str) -> ExitStatus {
-    let mut cmd = get_analysis_cmd(dir, out);
-    let exit_status = cmd
-        .spawn()
-        .expect("could not run cargo")
-        .wait()
-        .expect("failed to wait for cargo?");
-    if !exit_status.success() {
-        eprintln!("cargo error code: {}", exit_status.code().unwrap_or(-1));
-    };
-
-    exit_status
+/// Removes the analyzed packages' artifacts from the analysis target directory.
+///
+/// Cargo would otherwise consider the packages fresh and skip the lockbud wrapper,
+/// while dependencies are kept so that only the workspace crates are recompiled.
+pub fn get_clean_cmd(dir: &str, config: &CargoConfig) -> Option<Command> {
+    let ws_dir = Path::new(dir);
+    clean_cmd(ws_dir, &load_project_model(ws_dir)?, config)
 }
 
-pub fn cargo_clean(cwd: &str) -> ExitStatus {
+fn clean_cmd(ws_dir: &Path, model: &ProjectModel, config: &CargoConfig) -> Option<Command> {
+    let packages = model.packages_in(ws_dir);
+    if packages.is_empty() {
+        return None;
+    }
+
     let mut cmd = cargo();
+    cmd.env("CARGO_TARGET_DIR", analysis_target_dir(ws_dir));
     cmd.arg("clean");
-    cmd.current_dir(cwd);
-    let exit_status = cmd
-        .spawn()
-        .expect("could not run cargo")
-        .wait()
-        .expect("failed to wait for cargo?");
-    if !exit_status.success() {
-        eprintln!("cargo error code: {}", exit_status.code().unwrap_or(-1));
-    };
+    ProjectModel::package_args(&mut cmd, &packages);
+    cmd.args(config.profile_args());
+    cmd.current_dir(ws_dir);
+    cmd.stdout(Stdio::null());
 
-    exit_status
+    Some(cmd)
+}
+
+/// Commands to run, in order, for one analysis of the workspace at `dir`.
+pub fn get_analysis_cmds(dir: &str, out: &str, config: &CargoConfig) -> Vec<Command> {
+    let ws_dir = Path::new(dir);
+    eprintln!("running analysis in directory: {}", dir);
+    let model = load_project_model(ws_dir);
+
+    let mut cmds: Vec<Command> = model.as_ref().and_then(|m| clean_cmd(ws_dir, m, config)).into_iter().collect();
+    cmds.push(analysis_cmd(ws_dir, out, model.as_ref(), config));
+    cmds
+}
+
+/// Whether the `Cargo.toml` in `dir` declares a `[workspace]`.
+pub fn declares_workspace(dir: &Path) -> bool {
+    let manifest = fs::read_to_string(dir.join("Cargo.toml"))
+        .ok()
+        .and_then(|toml_str| toml_str.parse::<toml::Value>().ok());
+    match manifest {
+        Some(manifest) => manifest.get("workspace").is_some(),
+        None => false,
+    }
+}
+
+/// Finds the directory to analyze for `file` inside the innermost workspace
+/// folder containing it: the outermost Cargo workspace root on the way, so that
+/// all members get analyzed together, otherwise the nearest package root, or
+/// the workspace folder itself if there is no manifest on the way.
+pub fn find_analysis_root(file: &Path, workspace_roots: &[PathBuf]) -> Option<PathBuf> {
+    let workspace = workspace_roots
+        .iter()
+        .filter(|root| file.starts_with(root))
+        .max_by_key(|root| root.components().count())?;
+
+    let manifest_dirs: Vec<&Path> = file
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| dir.starts_with(workspace))
+        .filter(|dir| dir.join("Cargo.toml").is_file())
+        .collect();
+
+    let root = manifest_dirs
+        .iter()
+        .rev()
+        .find(|dir| declares_workspace(dir))
+        .or_else(|| manifest_dirs.first())
+        .copied()
+        .unwrap_or(workspace);
+
+    Some(root.to_path_buf())
+}
+
+/// The directories to analyze in the workspace folder `folder`: the analysis root,
+/// as `find_analysis_root` picks it, of every manifest under the folder.
+pub fn find_analysis_roots(folder: &Path) -> Vec<PathBuf> {
+    let mut manifests = Vec::new();
+    find_manifests(folder, &mut manifests);
+    let folders = [folder.to_path_buf()];
+    let mut roots: Vec<PathBuf> = manifests.iter().filter_map(|manifest| find_analysis_root(manifest, &folders)).collect();
+    roots.sort();
+    roots.dedup();
+    roots
+}
+
+/// Skips build output and hidden directories such as `.git` and `.rda`.
+fn find_manifests(dir: &Path, manifests: &mut Vec<PathBuf>) {
+    let manifest = dir.join("Cargo.toml");
+    if manifest.is_file() {
+        manifests.push(manifest);
+    }
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with('.') || name == "target" {
+            continue;
+        }
+        if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+            find_manifests(&entry.path(), manifests);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -116,22 +200,121 @@ mod tests {
 
     #[test]
     fn test_get_analysis_cmd() {
-        let cmd = get_analysis_cmd("123", "345");
+        let cmd = get_analysis_cmds("123", "345", &CargoConfig::default()).pop().unwrap();
 
         assert_eq!(cmd.get_current_dir().unwrap().to_str().unwrap(), "123");
         let res = cmd.get_envs().find(|x| x.0 == "__DL_OUT");
         assert_eq!(res.unwrap().1.unwrap().to_str().unwrap(), "345");
+        let res = cmd.get_envs().find(|x| x.0 == "__DL_STREAM");
+        assert_eq!(res.unwrap().1.unwrap().to_str().unwrap(), "345.ndjson");
+
+        let res = cmd.get_envs().find(|x| x.0 == "CARGO_TARGET_DIR");
+        assert_eq!(res.unwrap().1.unwrap().to_str().unwrap(), "123/.rda/target");
+    }
+
+    #[test]
+    fn test_get_clean_cmd() -> Result<(),Box<dyn Error>> {
+        let repo = ".tmp/fake_crate";
+        fs::create_dir_all(format!("{}/src", repo))?;
+        fs::write(format!("{}/Cargo.toml", repo), "[package]\nname = \"fake-crate\"\nversion = \"0.1.0\"\n")?;
+        fs::write(format!("{}/src/lib.rs", repo), "")?;
+        let repo = fs::canonicalize(repo)?;
+
+        let cmd = get_clean_cmd(repo.to_str().unwrap(), &CargoConfig::default()).unwrap();
+        let args: Vec<_> = cmd.get_args().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(args, vec!["clean", "-p", "fake-crate"]);
+
+        assert!(get_clean_cmd("not existed dir", &CargoConfig::default()).is_none());
+        assert_eq!(get_analysis_cmds("not existed dir", "345", &CargoConfig::default()).len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_analysis_cmd_instruments_crate_names() -> Result<(),Box<dyn Error>> {
+        let repo = ".tmp/fake_dashed_crate";
+        fs::create_dir_all(format!("{}/src", repo))?;
+        fs::write(format!("{}/Cargo.toml", repo), "[package]\nname = \"fake-dashed\"\nversion = \"0.1.0\"\n")?;
+        fs::write(format!("{}/src/main.rs", repo), "fn main() {}")?;
+        let repo = fs::canonicalize(repo)?;
+
+        let cmds = get_analysis_cmds(repo.to_str().unwrap(), "345", &CargoConfig::default());
+        assert_eq!(cmds.len(), 2);
+
+        let build = cmds.last().unwrap();
+        let args: Vec<_> = build.get_args().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(args, vec!["build", "--message-format=json", "-p", "fake-dashed"]);
+        let res = build.get_envs().find(|x| x.0 == "__DL_CRATE");
+        assert_eq!(res.unwrap().1.unwrap().to_str().unwrap(), "fake_dashed");
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_analysis_cmds_with_config() -> Result<(),Box<dyn Error>> {
+        let repo = ".tmp/fake_configured_crate";
+        fs::create_dir_all(format!("{}/src", repo))?;
+        fs::write(format!("{}/Cargo.toml", repo), "[package]\nname = \"fake-configured\"\nversion = \"0.1.0\"\n")?;
+        fs::write(format!("{}/src/lib.rs", repo), "")?;
+        let repo = fs::canonicalize(repo)?;
+
+        let mut config = CargoConfig { all_targets: true, release: true, ..CargoConfig::default() };
+        config.extra_env.insert("LOCKBUD_LOG".to_string(), "debug".to_string());
+        let cmds = get_analysis_cmds(repo.to_str().unwrap(), "345", &config);
+
+        let clean_args: Vec<_> = cmds[0].get_args().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(clean_args, vec!["clean", "-p", "fake-configured", "--release"]);
+
+        let build_args: Vec<_> = cmds[1].get_args().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(build_args, vec!["build", "--message-format=json", "-p", "fake-configured", "--all-targets", "--release"]);
+        let res = cmds[1].get_envs().find(|x| x.0 == "LOCKBUD_LOG");
+        assert_eq!(res.unwrap().1.unwrap().to_str().unwrap(), "debug");
+        Ok(())
+    }
 
+    #[test]
+    fn test_find_analysis_root() -> Result<(),Box<dyn Error>> {
+        let ws = fs::canonicalize(".")?.join(".tmp/fake_multi_root");
+        let nested = ws.join("nested");
+        fs::create_dir_all(nested.join("src"))?;
+        fs::create_dir_all(ws.join("other"))?;
+        fs::write(nested.join("Cargo.toml"), "[package]\nname = \"nested\"\n")?;
+
+        let roots = vec![PathBuf::from("/not/related"), ws.clone()];
+        assert_eq!(find_analysis_root(&nested.join("src/lib.rs"), &roots), Some(nested.clone()));
+        assert_eq!(find_analysis_root(&ws.join("other/lib.rs"), &roots), Some(ws.clone()));
+        assert_eq!(find_analysis_root(Path::new("/elsewhere/lib.rs"), &roots), None);
+
+        // Members of a cargo workspace are analyzed from the workspace root.
+        fs::write(ws.join("Cargo.toml"), "[workspace]\nmembers = [\"nested\"]\n")?;
+        assert_eq!(find_analysis_root(&nested.join("src/lib.rs"), &roots), Some(ws.clone()));
+        fs::remove_file(ws.join("Cargo.toml"))?;
+        Ok(())
     }
 
+    #[test]
+    fn test_find_analysis_roots() -> Result<(),Box<dyn Error>> {
+        let folder = fs::canonicalize(".")?.join(".tmp/fake_nested_folder");
+        let _ = fs::remove_dir_all(&folder);
+        for dir in ["app", "libs/ws/member", "libs/ws/target/package", "libs/.hidden"] {
+            fs::create_dir_all(folder.join(dir))?;
+            fs::write(folder.join(dir).join("Cargo.toml"), "[package]\nname = \"p\"\n")?;
+        }
+        fs::write(folder.join("libs/ws/Cargo.toml"), "[workspace]\nmembers = [\"member\"]\n")?;
+
+        // No manifest at the folder root, the member is analyzed with its workspace.
+        assert_eq!(find_analysis_roots(&folder), vec![folder.join("app"), folder.join("libs/ws")]);
+        assert!(find_analysis_roots(&folder.join("missing")).is_empty());
+        Ok(())
+    }
 
     #[test]
     fn test_run_analysis_in_dir() -> Result<(),Box<dyn Error>> {
         let repo = ".tmp/fake_repo";
         fs::create_dir_all(repo)?;
 
-        let res = run_analysis_in_dir(repo, "not existed output");
-        assert!(res.success());
+        for mut cmd in get_analysis_cmds(repo, "not existed output", &CargoConfig::default()) {
+            // Reads the piped output to the end so cargo never blocks on a full pipe.
+            assert!(cmd.output()?.status.success());
+        }
 
         Ok(())
     }
@@ -0,0 +1,830 @@
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+
+use crate::{
+    cargo_output::BuildOutput,
+    config::{CargoConfig, Config},
+    doctor::{run_checks, Check},
+    fingerprint::{fingerprint_path, Fingerprint},
+    lsp::lockbud_ty::AnalysisResult,
+    process,
+    provenance,
+    result_stream::{stream_path, ResultStream},
+    utils::get_analysis_cmds,
+};
+
+/// How often the worker checks the running cargo process while waiting for new tasks.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How often the memory of the analysis process tree is measured.
+const MEMORY_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the output left in the pipes is read once cargo exited.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub enum Task {
+    Analyze(PathBuf),
+    Cancel(PathBuf),
+    /// Settings for the runs started after this task.
+    Configure(Config),
+    /// Checks the lockbud setup for the project in the directory, if any.
+    Check(Option<PathBuf>),
+    Shutdown,
+}
+
+pub enum Event {
+    /// A run started, also sent again when a newer request restarted it.
+    Started {
+        workspace: PathBuf,
+    },
+    Progress {
+        workspace: PathBuf,
+        message: String,
+    },
+    /// lockbud finished `crates` during the build. `result` holds the findings of the
+    /// crates finished so far in this run, and the last run's for the others.
+    Partial {
+        workspace: PathBuf,
+        crates: Vec<String>,
+        result: Box<AnalysisResult>,
+    },
+    Finished {
+        workspace: PathBuf,
+        result: Result<Box<AnalysisResult>, String>,
+        build: BuildOutput,
+        elapsed: Duration,
+        /// The result was reused because nothing it depends on changed.
+        cached: bool,
+    },
+    Cancelled {
+        workspace: PathBuf,
+    },
+    Checked {
+        checks: Vec<Check>,
+    },
+}
+
+enum OutputLine {
+    Stdout(String),
+    Stderr(String),
+}
+
+enum RunOutcome {
+    Exited(ExitStatus),
+    Superseded,
+    Cancelled,
+    Shutdown,
+    Failed(String),
+    /// A limit was hit and the process tree killed, holds the reason.
+    Killed(String),
+}
+
+/// Limits of one analysis run, taken from the config when the run starts.
+struct Limits {
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    memory_kb: Option<u64>,
+}
+
+impl Limits {
+    fn new(config: &Config, start: Instant) -> Self {
+        let timeout = match config.timeout_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        Limits {
+            timeout,
+            deadline: timeout.map(|t| start + t),
+            memory_kb: match config.memory_limit_mb {
+                0 => None,
+                mb => Some(mb * 1024),
+            },
+        }
+    }
+}
+
+type CmdFactory = fn(&str, &str, &CargoConfig) -> Vec<Command>;
+
+/// Runs lockbud analysis on a background thread.
+///
+/// The worker owns the spawned cargo process. A newer request for the workspace
+/// that is being analyzed kills the running build and restarts it, requests for
+/// other workspaces are queued.
+pub struct AnalysisWorker {
+    sender: Sender<Task>,
+    pub receiver: Receiver<Event>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl AnalysisWorker {
+    pub fn spawn() -> Self {
+        Self::spawn_with(get_analysis_cmds)
+    }
+
+    fn spawn_with(make_cmd: CmdFactory) -> Self {
+        let (task_sender, task_receiver) = unbounded();
+        let (event_sender, event_receiver) = unbounded();
+        let mut state = WorkerState {
+            make_cmd,
+            config: Config::default(),
+            tasks: task_receiver,
+            events: event_sender,
+            queue: VecDeque::new(),
+        };
+        let handle = thread::Builder::new()
+            .name("analysis-worker".to_string())
+            .spawn(move || state.run())
+            .expect("failed to spawn analysis worker");
+
+        Self {
+            sender: task_sender,
+            receiver: event_receiver,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn analyze(&self, workspace: PathBuf) {
+        self.send(Task::Analyze(workspace));
+    }
+
+    /// Analyzes `workspace` even if its stored result is up to date.
+    pub fn reanalyze(&self, workspace: PathBuf) {
+        let _ = std::fs::remove_file(fingerprint_path(&workspace));
+        self.analyze(workspace);
+    }
+
+    /// Stops the running or queued analyses of `workspace` and the roots nested in it.
+    pub fn cancel(&self, workspace: PathBuf) {
+        self.send(Task::Cancel(workspace));
+    }
+
+    pub fn configure(&self, config: Config) {
+        self.send(Task::Configure(config));
+    }
+
+    /// Runs the checks of `deadlock-lsp doctor` without blocking the caller.
+    pub fn check(&self, workspace: Option<PathBuf>) {
+        self.send(Task::Check(workspace));
+    }
+
+    fn send(&self, task: Task) {
+        if let Err(err) = self.sender.send(task) {
+            eprintln!("send analysis task error: {}", err);
+        }
+    }
+
+    /// Kills the running analysis, if any, and waits for the worker thread to exit.
+    pub fn shutdown(&mut self) {
+        let _ = self.sender.send(Task::Shutdown);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for AnalysisWorker {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+pub fn analysis_out_path(workspace: &Path) -> PathBuf {
+    workspace.join(".rda/a.json")
+}
+
+pub fn build_log_path(workspace: &Path) -> PathBuf {
+    workspace.join(".rda/build.log")
+}
+
+/// The stored result of `workspace` if it was produced with `fingerprint`.
+fn cached_result(workspace: &Path, fingerprint: Option<Fingerprint>) -> Option<AnalysisResult> {
+    if fingerprint.is_none() || Fingerprint::load(workspace) != fingerprint {
+        return None;
+    }
+    AnalysisResult::from_file(&analysis_out_path(workspace).to_string_lossy()).ok()
+}
+
+fn enqueue(queue: &mut VecDeque<PathBuf>, workspace: PathBuf) {
+    if !queue.contains(&workspace) {
+        queue.push_back(workspace);
+    }
+}
+
+struct WorkerState {
+    make_cmd: CmdFactory,
+    config: Config,
+    tasks: Receiver<Task>,
+    events: Sender<Event>,
+    queue: VecDeque<PathBuf>,
+}
+
+impl WorkerState {
+    fn run(&mut self) {
+        loop {
+            let workspace = match self.queue.pop_front() {
+                Some(ws) => ws,
+                None => match self.tasks.recv() {
+                    Ok(Task::Analyze(ws)) => ws,
+                    Ok(Task::Cancel(_)) => continue,
+                    Ok(Task::Configure(config)) => {
+                        self.config = config;
+                        continue;
+                    }
+                    Ok(Task::Check(workspace)) => {
+                        self.emit(Event::Checked { checks: run_checks(workspace.as_deref()) });
+                        continue;
+                    }
+                    Ok(Task::Shutdown) | Err(_) => return,
+                },
+            };
+
+            let event = match self.analyze(workspace) {
+                Some(event) => event,
+                None => return,
+            };
+            if self.events.send(event).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Runs one analysis, returning the event to report or `None` on shutdown.
+    fn analyze(&mut self, mut workspace: PathBuf) -> Option<Event> {
+        loop {
+            let start = Instant::now();
+            let analysis_out = analysis_out_path(&workspace);
+            let fingerprint = Fingerprint::compute(&workspace, &self.config.cargo);
+            if let Some(result) = cached_result(&workspace, fingerprint) {
+                eprintln!("analysis of {:?} is up to date", workspace);
+                return Some(Event::Finished {
+                    workspace,
+                    result: Ok(Box::new(result)),
+                    build: BuildOutput::default(),
+                    elapsed: start.elapsed(),
+                    cached: true,
+                });
+            }
+
+            self.emit(Event::Started { workspace: workspace.clone() });
+            let _ = std::fs::remove_file(&analysis_out);
+            let mut stream = ResultStream::restart(stream_path(&analysis_out));
+
+            let cmds = (self.make_cmd)(
+                &workspace.to_string_lossy(),
+                &analysis_out.to_string_lossy(),
+                &self.config.cargo,
+            );
+            // The build is the last command.
+            let metadata = cmds.last().map(|cmd| provenance::collect(&workspace, cmd));
+            let limits = Limits::new(&self.config, start);
+            let mut build = BuildOutput::default();
+            let outcome = self.run_cmds(cmds, &workspace, &limits, &mut build, &mut stream);
+
+            let result = match outcome {
+                RunOutcome::Superseded => {
+                    eprintln!("analysis of {:?} superseded by a newer request", workspace);
+                    // The restarted run is at the front of the queue.
+                    workspace = self.queue.pop_front()?;
+                    continue;
+                }
+                RunOutcome::Cancelled => return Some(Event::Cancelled { workspace }),
+                RunOutcome::Shutdown => return None,
+                RunOutcome::Failed(err) => Err(err),
+                RunOutcome::Killed(reason) => Err(format!(
+                    "analysis {} in crate {}",
+                    reason,
+                    build.current_crate.as_deref().unwrap_or("unknown")
+                )),
+                RunOutcome::Exited(status) if !status.success() => {
+                    Err(format!("cargo exited with code {}", status.code().unwrap_or(-1)))
+                }
+                RunOutcome::Exited(_) => {
+                    stream.finish();
+                    let result = if stream.exists() {
+                        stream.result()
+                    } else {
+                        AnalysisResult::from_file(&analysis_out.to_string_lossy())
+                            .map_err(|err| format!("read {:?}: {}", analysis_out, err))
+                    };
+                    result.map(|mut result| {
+                        result.metadata = metadata.map(|mut metadata| {
+                            provenance::finish(&mut metadata, start.elapsed());
+                            metadata
+                        });
+                        Box::new(result)
+                    })
+                }
+            };
+
+            if let Err(err) = build.write_log(&build_log_path(&workspace)) {
+                eprintln!("write build log error: {}", err);
+            }
+            if let Ok(result) = &result {
+                // Keeps the metadata with the result, also when it is reused later.
+                if let Err(err) = result.to_file(&analysis_out.to_string_lossy()) {
+                    eprintln!("write analysis result error: {}", err);
+                }
+                if let Some(fingerprint) = fingerprint {
+                    if let Err(err) = fingerprint.save(&workspace) {
+                        eprintln!("save fingerprint error: {}", err);
+                    }
+                }
+            }
+
+            return Some(Event::Finished {
+                workspace,
+                result,
+                build,
+                elapsed: start.elapsed(),
+                cached: false,
+            });
+        }
+    }
+
+    fn emit(&self, event: Event) {
+        let _ = self.events.send(event);
+    }
+
+    /// Runs `cmds` one after another, returning the outcome of the last one.
+    fn run_cmds(
+        &mut self,
+        cmds: Vec<Command>,
+        workspace: &Path,
+        limits: &Limits,
+        build: &mut BuildOutput,
+        stream: &mut ResultStream,
+    ) -> RunOutcome {
+        let mut outcome = RunOutcome::Failed("no command to run".to_string());
+        for mut cmd in cmds {
+            // Lets a limit or a newer request kill rustc and lockbud along with cargo.
+            process::new_process_group(&mut cmd);
+            outcome = match cmd.spawn() {
+                Ok(child) => self.wait_or_supersede(child, workspace, limits, build, stream),
+                Err(err) => RunOutcome::Failed(format!("could not run {:?}: {}", cmd, err)),
+            };
+            match &outcome {
+                RunOutcome::Exited(status) if !status.success() => {
+                    eprintln!("{:?} error code: {}", cmd, status.code().unwrap_or(-1));
+                }
+                RunOutcome::Exited(_) => {}
+                _ => return outcome,
+            }
+        }
+        outcome
+    }
+
+    fn record(&self, workspace: &Path, build: &mut BuildOutput, line: OutputLine) {
+        match line {
+            OutputLine::Stdout(line) => {
+                if build.push_stdout(&line) {
+                    if let Some(message) = build.progress_message() {
+                        self.emit(Event::Progress { workspace: workspace.to_path_buf(), message });
+                    }
+                }
+            }
+            OutputLine::Stderr(line) => {
+                if build.push_stderr(&line) {
+                    if let Some(message) = build.progress_message() {
+                        self.emit(Event::Progress { workspace: workspace.to_path_buf(), message });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Publishes the findings streamed so far once more crates are finished.
+    fn poll_stream(&self, workspace: &Path, stream: &mut ResultStream) {
+        let crates = stream.poll();
+        if crates.is_empty() {
+            return;
+        }
+        match stream.partial_result() {
+            Ok(result) => self.emit(Event::Partial {
+                workspace: workspace.to_path_buf(),
+                crates,
+                result: Box::new(result),
+            }),
+            // The final result reports it.
+            Err(err) => eprintln!("partial analysis result: {}", err),
+        }
+    }
+
+    /// Waits for `child` to exit while handling tasks that arrive in the meantime,
+    /// following the result stream and enforcing `limits`.
+    fn wait_or_supersede(
+        &mut self,
+        mut child: Child,
+        workspace: &Path,
+        limits: &Limits,
+        build: &mut BuildOutput,
+        stream: &mut ResultStream,
+    ) -> RunOutcome {
+        let lines = capture(&mut child);
+        let mut last_memory_check = Instant::now();
+        loop {
+            for line in lines.try_iter() {
+                self.record(workspace, build, line);
+            }
+            self.poll_stream(workspace, stream);
+
+            match process::has_exited(&mut child) {
+                Ok(true) => {
+                    // Processes left behind by cargo would keep the pipes open.
+                    let status = match process::kill_group_of_exited(&mut child) {
+                        Ok(status) => status,
+                        Err(err) => return RunOutcome::Failed(format!("failed to wait for cargo: {}", err)),
+                    };
+                    let deadline = Instant::now() + DRAIN_TIMEOUT;
+                    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+                        match lines.recv_timeout(left) {
+                            Ok(line) => self.record(workspace, build, line),
+                            Err(_) => break,
+                        }
+                    }
+                    return RunOutcome::Exited(status);
+                }
+                Ok(false) => {}
+                Err(err) => return RunOutcome::Failed(format!("failed to wait for cargo: {}", err)),
+            }
+
+            if let (Some(deadline), Some(timeout)) = (limits.deadline, limits.timeout) {
+                if Instant::now() >= deadline {
+                    process::kill_tree(&mut child);
+                    return RunOutcome::Killed(format!("timed out after {} s", timeout.as_secs()));
+                }
+            }
+            if let Some(limit_kb) = limits.memory_kb {
+                if last_memory_check.elapsed() >= MEMORY_INTERVAL {
+                    last_memory_check = Instant::now();
+                    if let Some(used_kb) = process::group_memory_kb(child.id()) {
+                        if used_kb > limit_kb {
+                            process::kill_tree(&mut child);
+                            return RunOutcome::Killed(format!(
+                                "exceeded the memory limit of {} MiB",
+                                limit_kb / 1024
+                            ));
+                        }
+                    }
+                }
+            }
+
+            match self.tasks.recv_timeout(POLL_INTERVAL) {
+                Ok(Task::Analyze(next)) if next == workspace => {
+                    process::kill_tree(&mut child);
+                    self.queue.retain(|ws| ws != &next);
+                    self.queue.push_front(next);
+                    return RunOutcome::Superseded;
+                }
+                Ok(Task::Analyze(next)) => enqueue(&mut self.queue, next),
+                Ok(Task::Cancel(ws)) => {
+                    // Also the analysis roots nested in a removed workspace folder.
+                    self.queue.retain(|queued| !queued.starts_with(&ws));
+                    if workspace.starts_with(&ws) {
+                        process::kill_tree(&mut child);
+                        return RunOutcome::Cancelled;
+                    }
+                }
+                Ok(Task::Configure(config)) => self.config = config,
+                Ok(Task::Check(workspace)) => self.emit(Event::Checked { checks: run_checks(workspace.as_deref()) }),
+                Ok(Task::Shutdown) | Err(RecvTimeoutError::Disconnected) => {
+                    process::kill_tree(&mut child);
+                    return RunOutcome::Shutdown;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+        }
+    }
+}
+
+/// Forwards the lines of the piped stdout and stderr of `child`, if any.
+/// The channel disconnects once both pipes are closed.
+fn capture(child: &mut Child) -> Receiver<OutputLine> {
+    let (sender, receiver) = unbounded();
+    if let Some(stdout) = child.stdout.take() {
+        forward_lines(stdout, sender.clone(), OutputLine::Stdout);
+    }
+    if let Some(stderr) = child.stderr.take() {
+        forward_lines(stderr, sender, OutputLine::Stderr);
+    }
+    receiver
+}
+
+fn forward_lines<R: Read + Send + 'static>(
+    pipe: R,
+    sender: Sender<OutputLine>,
+    wrap: fn(String) -> OutputLine,
+) {
+    thread::spawn(move || {
+        for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+            if sender.send(wrap(line)).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, fs, process::Stdio};
+
+    use super::*;
+
+    fn sleep_cmd(dir: &str, _out: &str, _config: &CargoConfig) -> Vec<Command> {
+        let mut cmd = Command::new("sleep");
+        cmd.arg("5");
+        cmd.current_dir(dir);
+        vec![cmd]
+    }
+
+    fn write_result_cmd(dir: &str, out: &str, _config: &CargoConfig) -> Vec<Command> {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd.arg(format!(
+            "mkdir -p .rda && echo '{{\"calls\":[],\"critical_sections\":[]}}' > {}",
+            out
+        ));
+        cmd.current_dir(dir);
+        vec![Command::new("true"), cmd]
+    }
+
+    fn failing_cmd(dir: &str, _out: &str, _config: &CargoConfig) -> Vec<Command> {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd.arg("echo 'error: could not compile `foo`' >&2; exit 101");
+        cmd.current_dir(dir);
+        cmd.stderr(Stdio::piped());
+        vec![cmd]
+    }
+
+    fn artifact_cmd(dir: &str, _out: &str, _config: &CargoConfig) -> Vec<Command> {
+        let mut cmd = Command::new("echo");
+        cmd.arg(r#"{"reason":"compiler-artifact","target":{"name":"foo"}}"#);
+        cmd.current_dir(dir);
+        cmd.stdout(Stdio::piped());
+        vec![cmd]
+    }
+
+    fn lingering_cmd(dir: &str, _out: &str, _config: &CargoConfig) -> Vec<Command> {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        // The background process keeps stdout open after the shell exited.
+        cmd.arg("sleep 30 & echo 'error: lingering' >&2; exit 101");
+        cmd.current_dir(dir);
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        vec![cmd]
+    }
+
+    #[test]
+    fn test_worker_reports_finished_analysis() -> Result<(), Box<dyn Error>> {
+        let repo = fs::canonicalize(".")?.join(".tmp/worker_repo");
+        fs::create_dir_all(&repo)?;
+
+        let worker = AnalysisWorker::spawn_with(write_result_cmd);
+        worker.analyze(repo.clone());
+
+        match worker.receiver.recv_timeout(Duration::from_secs(5))? {
+            Event::Started { workspace } => assert_eq!(workspace, repo),
+            _ => panic!("expected the run to start"),
+        }
+        match worker.receiver.recv_timeout(Duration::from_secs(5))? {
+            Event::Finished { workspace, result, .. } => {
+                assert_eq!(workspace, repo);
+                let metadata = result.unwrap().metadata.unwrap();
+                assert_eq!(metadata.cargo_args[0], "-c");
+                assert!(AnalysisResult::from_file(&analysis_out_path(&repo).to_string_lossy())?.metadata.is_some());
+            }
+            _ => panic!("expected the run to finish"),
+        }
+        Ok(())
+    }
+
+    fn streaming_cmd(dir: &str, out: &str, _config: &CargoConfig) -> Vec<Command> {
+        let record = |krate: &str| format!(
+            r#"{{"record":"call","crate":"{}","call":{{"callchains":[["/a.rs",1,2,1,9]],"ty":"ChRecv"}}}}"#,
+            krate
+        );
+        let done = |krate: &str| format!(r#"{{"record":"crate_done","crate":"{}"}}"#, krate);
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd.arg(format!(
+            "mkdir -p .rda && echo '{}' >> {stream} && echo '{}' >> {stream} && sleep 1 && echo '{}' >> {stream} && echo '{}' >> {stream}",
+            record("foo"),
+            done("foo"),
+            record("bar"),
+            done("bar"),
+            stream = stream_path(Path::new(out)).display(),
+        ));
+        cmd.current_dir(dir);
+        vec![cmd]
+    }
+
+    #[test]
+    fn test_worker_streams_results_by_crate() -> Result<(), Box<dyn Error>> {
+        let repo = fs::canonicalize(".")?.join(".tmp/worker_repo_stream");
+        let _ = fs::remove_dir_all(&repo);
+        fs::create_dir_all(&repo)?;
+
+        let worker = AnalysisWorker::spawn_with(streaming_cmd);
+        let run = || {
+            worker.analyze(repo.clone());
+            let partial = worker.receiver.iter().find_map(|e| match e {
+                Event::Partial { crates, result, .. } => Some((crates, result.calls.len())),
+                Event::Finished { .. } => panic!("expected findings before the build finished"),
+                _ => None,
+            });
+            let finished = worker.receiver.iter().find_map(|e| match e {
+                Event::Finished { result, .. } => Some(result.map(|result| result.calls.len())),
+                _ => None,
+            });
+            (partial, finished)
+        };
+        assert_eq!(run(), (Some((vec!["foo".to_string()], 1)), Some(Ok(2))));
+        // Kept as the result file for reuse.
+        assert_eq!(AnalysisResult::from_file(&analysis_out_path(&repo).to_string_lossy())?.calls.len(), 2);
+
+        // The findings of bar from the first run stay until bar is analyzed again.
+        assert_eq!(run(), (Some((vec!["foo".to_string()], 2)), Some(Ok(2))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_worker_reuses_unchanged_result() -> Result<(), Box<dyn Error>> {
+        let repo = fs::canonicalize(".")?.join(".tmp/worker_repo_cached");
+        let _ = fs::remove_dir_all(&repo);
+        fs::create_dir_all(repo.join("src"))?;
+        fs::write(repo.join("Cargo.toml"), "[package]\nname = \"worker-cached\"\nversion = \"0.1.0\"\n")?;
+        fs::write(repo.join("src/lib.rs"), "")?;
+
+        let worker = AnalysisWorker::spawn_with(write_result_cmd);
+        let finished = |force: bool| {
+            match force {
+                true => worker.reanalyze(repo.clone()),
+                false => worker.analyze(repo.clone()),
+            }
+            worker.receiver.iter().find_map(|e| match e {
+                Event::Finished { result, cached, .. } => Some((result.is_ok(), cached)),
+                _ => None,
+            })
+        };
+        assert_eq!(finished(false), Some((true, false)));
+        assert_eq!(finished(false), Some((true, true)));
+
+        fs::write(repo.join("src/lib.rs"), "pub fn f() {}")?;
+        assert_eq!(finished(false), Some((true, false)));
+
+        // An explicit reanalysis does not reuse the result.
+        assert_eq!(finished(true), Some((true, false)));
+        assert_eq!(finished(false), Some((true, true)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_worker_captures_failed_build() -> Result<(), Box<dyn Error>> {
+        let repo = fs::canonicalize(".")?.join(".tmp/worker_repo_failed");
+        fs::create_dir_all(&repo)?;
+
+        let worker = AnalysisWorker::spawn_with(failing_cmd);
+        worker.analyze(repo.clone());
+
+        let finished = worker.receiver.iter().find(|e| matches!(e, Event::Finished { .. }));
+        let (result, build) = match finished {
+            Some(Event::Finished { result, build, .. }) => (result, build),
+            _ => panic!("expected the run to finish"),
+        };
+        assert_eq!(result.unwrap_err(), "cargo exited with code 101");
+        assert_eq!(build.stderr, vec!["error: could not compile `foo`"]);
+        assert_eq!(build.log_path, Some(build_log_path(&repo)));
+        assert!(fs::read_to_string(build_log_path(&repo))?.contains("could not compile"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_worker_does_not_wait_for_processes_left_behind() -> Result<(), Box<dyn Error>> {
+        let repo = fs::canonicalize(".")?.join(".tmp/worker_repo_lingering");
+        fs::create_dir_all(&repo)?;
+
+        let start = Instant::now();
+        let worker = AnalysisWorker::spawn_with(lingering_cmd);
+        worker.analyze(repo);
+
+        let finished = worker.receiver.iter().find_map(|e| match e {
+            Event::Finished { result, build, .. } => Some((result, build)),
+            _ => None,
+        });
+        let (result, build) = finished.unwrap();
+        assert_eq!(result.unwrap_err(), "cargo exited with code 101");
+        assert_eq!(build.stderr, vec!["error: lingering"]);
+        assert!(start.elapsed() < Duration::from_secs(10));
+        Ok(())
+    }
+
+    #[test]
+    fn test_worker_shutdown_kills_running_analysis() -> Result<(), Box<dyn Error>> {
+        let repo = fs::canonicalize(".")?.join(".tmp/worker_repo_shutdown");
+        fs::create_dir_all(&repo)?;
+
+        let start = Instant::now();
+        let mut worker = AnalysisWorker::spawn_with(sleep_cmd);
+        worker.analyze(repo);
+        thread::sleep(POLL_INTERVAL * 2);
+        worker.shutdown();
+
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(worker.receiver.try_iter().all(|e| matches!(e, Event::Started { .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn test_worker_cancel_kills_running_analysis() -> Result<(), Box<dyn Error>> {
+        let repo = fs::canonicalize(".")?.join(".tmp/worker_repo_cancel");
+        fs::create_dir_all(&repo)?;
+
+        let start = Instant::now();
+        let worker = AnalysisWorker::spawn_with(sleep_cmd);
+        worker.analyze(repo.clone());
+        thread::sleep(POLL_INTERVAL * 2);
+        worker.cancel(repo.clone());
+
+        let cancelled = worker.receiver.iter().find(|e| !matches!(e, Event::Started { .. }));
+        match cancelled {
+            Some(Event::Cancelled { workspace }) => assert_eq!(workspace, repo),
+            _ => panic!("expected the run to be cancelled"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_worker_cancel_covers_nested_roots() -> Result<(), Box<dyn Error>> {
+        let folder = fs::canonicalize(".")?.join(".tmp/worker_repo_nested");
+        let (first, second) = (folder.join("a"), folder.join("b"));
+        fs::create_dir_all(&first)?;
+        fs::create_dir_all(&second)?;
+
+        let worker = AnalysisWorker::spawn_with(sleep_cmd);
+        worker.analyze(first.clone());
+        worker.analyze(second);
+        thread::sleep(POLL_INTERVAL * 2);
+        worker.cancel(folder);
+
+        let cancelled = worker.receiver.iter().find(|e| !matches!(e, Event::Started { .. }));
+        match cancelled {
+            Some(Event::Cancelled { workspace }) => assert_eq!(workspace, first),
+            _ => panic!("expected the run to be cancelled"),
+        }
+        // The queued root does not start.
+        assert!(worker.receiver.recv_timeout(POLL_INTERVAL * 5).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_worker_kills_analysis_after_timeout() -> Result<(), Box<dyn Error>> {
+        let repo = fs::canonicalize(".")?.join(".tmp/worker_repo_timeout");
+        fs::create_dir_all(&repo)?;
+
+        let start = Instant::now();
+        let worker = AnalysisWorker::spawn_with(sleep_cmd);
+        worker.configure(Config { timeout_secs: 1, ..Config::default() });
+        worker.analyze(repo);
+
+        let finished = worker.receiver.iter().find_map(|e| match e {
+            Event::Finished { result, .. } => Some(result),
+            _ => None,
+        });
+        assert_eq!(finished.unwrap().unwrap_err(), "analysis timed out after 1 s in crate unknown");
+        assert!(start.elapsed() < Duration::from_secs(4));
+        Ok(())
+    }
+
+    #[test]
+    fn test_worker_reports_progress() -> Result<(), Box<dyn Error>> {
+        let repo = fs::canonicalize(".")?.join(".tmp/worker_repo_progress");
+        fs::create_dir_all(&repo)?;
+
+        let worker = AnalysisWorker::spawn_with(artifact_cmd);
+        worker.analyze(repo);
+
+        let progress = worker.receiver.iter().find_map(|e| match e {
+            Event::Progress { message, .. } => Some(message),
+            _ => None,
+        });
+        assert_eq!(progress, Some("foo (1 crates built)".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_enqueue_dedups_workspaces() {
+        let mut queue = VecDeque::new();
+        enqueue(&mut queue, PathBuf::from("/a"));
+        enqueue(&mut queue, PathBuf::from("/b"));
+        enqueue(&mut queue, PathBuf::from("/a"));
+        assert_eq!(queue.len(), 2);
+    }
+}